@@ -0,0 +1,81 @@
+use std::io;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+/// Orders the commit of one [`Sponge`] after another `Sponge`'s commit has reached stable storage.
+///
+/// One `Sponge` releases the barrier with [`Sponge::commit_before`], once it has been renamed
+/// into place, and the file and its directory have been synced. Others wait for it in
+/// [`Sponge::commit_after`], so their new content can't become visible before the first
+/// `Sponge`'s is durable. This is the ordering a write-ahead log needs.
+///
+/// The barrier can be shared between threads. If the first commit fails, waiting commits fail
+/// too, without renaming anything.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::io::Write;
+/// # let dir = tempfile::tempdir().unwrap();
+/// let barrier = tempfile_fast::Barrier::new();
+/// let mut log = tempfile_fast::Sponge::new_for(dir.path().join("log")).unwrap();
+/// let mut data = tempfile_fast::Sponge::new_for(dir.path().join("data")).unwrap();
+/// log.write_all(b"intent").unwrap();
+/// data.write_all(b"state").unwrap();
+///
+/// log.commit_before(&barrier).unwrap();
+/// data.commit_after(&barrier).unwrap();
+/// ```
+///
+/// [`Sponge`]: struct.Sponge.html
+/// [`Sponge::commit_before`]: struct.Sponge.html#method.commit_before
+/// [`Sponge::commit_after`]: struct.Sponge.html#method.commit_after
+#[derive(Debug, Default)]
+pub struct Barrier {
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Waiting,
+    Synced,
+    Failed(io::ErrorKind, String),
+}
+
+impl Barrier {
+    /// Create a barrier, which has not been released.
+    pub fn new() -> Barrier {
+        Barrier::default()
+    }
+
+    pub(crate) fn release(&self, result: &io::Result<()>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = match result {
+            Ok(()) => State::Synced,
+            Err(e) => State::Failed(e.kind(), e.to_string()),
+        };
+        self.released.notify_all();
+    }
+
+    /// Block until the barrier is released, returning an error if the commit it was waiting for
+    /// failed.
+    pub(crate) fn wait(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            match *state {
+                State::Waiting => {
+                    state = self.released.wait(state).unwrap_or_else(|e| e.into_inner())
+                }
+                State::Synced => return Ok(()),
+                State::Failed(kind, ref message) => {
+                    return Err(io::Error::new(
+                        kind,
+                        format!("barrier's commit failed: {message}"),
+                    ))
+                }
+            }
+        }
+    }
+}
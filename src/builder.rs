@@ -0,0 +1,228 @@
+use std::io;
+use std::path::Path;
+
+use crate::linux;
+use crate::PersistableTempFile;
+
+/// Options for creating a [`PersistableTempFile`].
+///
+/// [`PersistableTempFile::new_in`] is the same as `Builder::new().tempfile_in(dir)`.
+///
+/// [`PersistableTempFile`]: enum.PersistableTempFile.html
+/// [`PersistableTempFile::new_in`]: enum.PersistableTempFile.html#method.new_in
+#[derive(Clone, Debug)]
+pub struct Builder {
+    custom_flags: i32,
+    mode: u32,
+    noatime: bool,
+    probe_link: bool,
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder {
+            custom_flags: 0,
+            mode: 0o600,
+            noatime: false,
+            probe_link: false,
+        }
+    }
+}
+
+impl Builder {
+    /// Create a builder with the default options.
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    /// The permissions (`mode`) to create the temporary file with, before the process' `umask`
+    /// is applied. Default: `0o600`, readable and writable only by the owner.
+    ///
+    /// This is used for both the `Linux` fast path and the `Fallback`. Setting it to the final
+    /// mode avoids a window where a freshly persisted file briefly has the wrong permissions,
+    /// before they are fixed up (e.g. by a `Sponge`'s `commit()`). Most users should keep the
+    /// secure default.
+    ///
+    /// Ignored on non-`unix` platforms.
+    pub fn temp_mode(&mut self, mode: u32) -> &mut Builder {
+        self.mode = mode;
+        self
+    }
+
+    /// Additional flags to pass to `open()` when creating the `Linux` fast-path file, e.g.
+    /// `libc::O_NOATIME`. They are combined with the crate's own flags
+    /// (`O_TMPFILE | O_RDWR | O_CLOEXEC`), which can't be removed.
+    ///
+    /// Flags which would change the access mode, or stop the file from being persisted
+    /// (`O_PATH`, `O_CREAT`, `O_EXCL`), are rejected with [`io::ErrorKind::InvalidInput`]
+    /// when the file is created.
+    ///
+    /// If the kernel refuses the flags with `EINVAL` (e.g. `O_DIRECT` on tmpfs), that can't be
+    /// told apart from `O_TMPFILE` itself being unsupported, so the `Fallback` is used, without
+    /// them. Any other refusal, e.g. `EPERM` or `EACCES`, is returned as the error.
+    ///
+    /// The flags are ignored on other platforms, and for the `Fallback`.
+    pub fn custom_flags(&mut self, flags: i32) -> &mut Builder {
+        self.custom_flags = flags;
+        self
+    }
+
+    /// Open the `Linux` fast-path file with `O_NOATIME`, so reading it back doesn't update its
+    /// access time. Default: `false`.
+    ///
+    /// `O_NOATIME` is only allowed for the file's owner, which the creator of an `O_TMPFILE`
+    /// always is. This is a minor optimisation, saving some metadata writes, which is only
+    /// likely to matter when creating, and reading back, a lot of temporary files.
+    ///
+    /// This does nothing on other platforms, or for the `Fallback`.
+    pub fn noatime(&mut self, noatime: bool) -> &mut Builder {
+        self.noatime = noatime;
+        self
+    }
+
+    /// Check that the `Linux` fast-path file can really be persisted, before using it, and use
+    /// the `Fallback` if not. Default: `false`.
+    ///
+    /// Some network filesystems accept creating an `O_TMPFILE` file, but then refuse to give it
+    /// a name, so the failure would only show up when persisting it. The check is done once per
+    /// filesystem (device) per process, by naming, and removing, a throwaway file in the
+    /// directory; after that, it's free. Only the filesystem refusing the name (`EXDEV`,
+    /// `EPERM` or `EOPNOTSUPP`) is remembered as a "no"; if the check can't be done, e.g. the
+    /// directory is full, the fast path is used, and it's checked again next time.
+    ///
+    /// This does nothing on other platforms, or for [`tempfile_in_dir_fd`](#method.tempfile_in_dir_fd).
+    pub fn probe_link(&mut self, probe: bool) -> &mut Builder {
+        self.probe_link = probe;
+        self
+    }
+
+    /// The extra flags to create the `Linux` fast-path file with.
+    fn flags(&self) -> i32 {
+        if self.noatime {
+            self.custom_flags | linux::O_NOATIME
+        } else {
+            self.custom_flags
+        }
+    }
+
+    /// Create a temporary file in a given filesystem, or, if the filesystem
+    /// does not support creating secure temporary files, create a
+    /// [`tempfile::NamedTempFile`].
+    ///
+    /// Only a lack of support (`EOPNOTSUPP`, `EISDIR` from kernels which predate `O_TMPFILE`,
+    /// or `EINVAL`) falls back; other errors, such as the directory not existing, or not being
+    /// writable, are returned directly.
+    ///
+    /// [`tempfile::NamedTempFile`]: https://docs.rs/tempfile/*/tempfile/struct.NamedTempFile.html
+    pub fn tempfile_in<P: AsRef<Path>>(&self, dir: P) -> io::Result<PersistableTempFile> {
+        self.tempfile_in_or(dir, |dir| self.fallback_in(dir))
+    }
+
+    /// [`tempfile_in`](#method.tempfile_in), but make the `Fallback` with `fallback`, instead
+    /// of this builder's options.
+    pub(crate) fn tempfile_in_with<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        fallback: &tempfile::Builder,
+    ) -> io::Result<PersistableTempFile> {
+        self.tempfile_in_or(dir, |dir| {
+            Ok(PersistableTempFile::Fallback(fallback.tempfile_in(dir)?))
+        })
+    }
+
+    /// [`tempfile_in`](#method.tempfile_in), but fail, instead of making a `Fallback`.
+    pub(crate) fn tempfile_in_exclusive<P: AsRef<Path>>(
+        &self,
+        dir: P,
+    ) -> io::Result<PersistableTempFile> {
+        self.tempfile_in_or(dir, |dir| {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("can't create an anonymous (O_TMPFILE) temporary file in {dir:?}"),
+            ))
+        })
+    }
+
+    fn tempfile_in_or<P, F>(&self, dir: P, fallback: F) -> io::Result<PersistableTempFile>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&Path) -> io::Result<PersistableTempFile>,
+    {
+        linux::check_custom_flags(self.custom_flags)?;
+
+        let dir = dir.as_ref();
+        match linux::create_nonexclusive_tempfile_in(dir, self.flags(), self.mode) {
+            Ok(file) if self.probe_link && !linux::tmpfile_links(&file, dir) => fallback(dir),
+            Ok(file) => Ok(PersistableTempFile::Linux(file)),
+            Err(ref e) if io::ErrorKind::Unsupported == e.kind() => fallback(dir),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create a temporary file in an already-open directory, like
+    /// [`tempfile_in`](#method.tempfile_in).
+    ///
+    /// On Linux, the directory isn't looked up again: the file is created relative to `dir`,
+    /// even if the directory has since been renamed. This also saves resolving the path for
+    /// every file, when creating many. Use
+    /// [`PersistableTempFile::persist_noclobber_at`](enum.PersistableTempFile.html#method.persist_noclobber_at)
+    /// to give it a name in the same directory.
+    ///
+    /// The `Fallback` is created using the directory's current path, as reported by `/proc`.
+    /// If that path doesn't lead back to `dir`, e.g. because `dir` is a detached mount, from
+    /// `open_tree()`, or is in another mount namespace, this fails with
+    /// [`io::ErrorKind::Unsupported`], instead of creating the file somewhere else. On other
+    /// platforms, this also fails with [`io::ErrorKind::Unsupported`].
+    #[cfg(unix)]
+    pub fn tempfile_in_dir_fd(
+        &self,
+        dir: std::os::fd::BorrowedFd,
+    ) -> io::Result<PersistableTempFile> {
+        use std::os::fd::AsRawFd;
+
+        linux::check_custom_flags(self.custom_flags)?;
+
+        match linux::create_nonexclusive_tempfile_at(dir.as_raw_fd(), self.flags(), self.mode) {
+            Ok(file) => Ok(PersistableTempFile::Linux(file)),
+            Err(ref e) if io::ErrorKind::Unsupported == e.kind() => {
+                let path = std::fs::read_link(linux::fd_path(dir.as_raw_fd())?)?;
+                if !is_path_of(dir, &path)? {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!(
+                            "O_TMPFILE is not supported here, and the directory can't be \
+                             reached by its path, {path:?}, to create a named file instead"
+                        ),
+                    ));
+                }
+                self.fallback_in(path)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn fallback_in<P: AsRef<Path>>(&self, dir: P) -> io::Result<PersistableTempFile> {
+        let mut builder = tempfile::Builder::new();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            builder.permissions(std::fs::Permissions::from_mode(self.mode));
+        }
+
+        Ok(PersistableTempFile::Fallback(builder.tempfile_in(dir)?))
+    }
+}
+
+/// Whether `path` currently leads to the directory `dir` is open on.
+#[cfg(unix)]
+fn is_path_of(dir: std::os::fd::BorrowedFd, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let open = std::fs::File::from(dir.try_clone_to_owned()?).metadata()?;
+    Ok(match path.metadata() {
+        Ok(found) => found.dev() == open.dev() && found.ino() == open.ino(),
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => false,
+        Err(e) => return Err(e),
+    })
+}
@@ -0,0 +1,184 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+
+use crate::linux;
+
+/// Replace the contents of `dest` with an independent copy of all of `src`.
+///
+/// The cheapest available method is used: a copy-on-write reflink (btrfs, xfs), an in-kernel
+/// `copy_file_range`, or, finally, reading and writing through userspace.
+///
+/// The positions of both files are unspecified afterwards.
+pub fn reflink_or_copy(src: &fs::File, dest: &fs::File) -> io::Result<()> {
+    reflink_or_copy_with(src, dest, linux::reflink, linux::copy_range)
+}
+
+/// `reflink_or_copy`, with the reflink done by `reflink`, and the in-kernel copy by `copy_range`.
+fn reflink_or_copy_with(
+    src: &fs::File,
+    dest: &fs::File,
+    reflink: fn(&fs::File, &fs::File) -> io::Result<()>,
+    copy_range: fn(&fs::File, &fs::File) -> io::Result<u64>,
+) -> io::Result<()> {
+    // FICLONE only ever grows `dest`, so a longer `dest` would keep its old tail
+    dest.set_len(0)?;
+
+    if reflink(src, dest).is_ok() {
+        return Ok(());
+    }
+
+    if copy_range(src, dest).is_ok() {
+        return Ok(());
+    }
+
+    // copy_file_range may have failed part-way through
+    dest.set_len(0)?;
+
+    let mut src = src;
+    let mut dest = dest;
+    src.seek(SeekFrom::Start(0))?;
+    dest.seek(SeekFrom::Start(0))?;
+    io::copy(&mut src, &mut dest)?;
+    Ok(())
+}
+
+/// Make `len` bytes of `dest`, at `offset`, a copy of the same range of `src`.
+///
+/// This is a copy-on-write clone where the filesystem supports it, and the range is suitably
+/// aligned; otherwise, the data is read and written through userspace, stopping early if `src`
+/// ends first.
+///
+/// The positions of both files are unspecified afterwards.
+pub fn clone_or_copy_range(
+    src: &fs::File,
+    dest: &fs::File,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    if linux::clone_range(src, dest, offset, len).is_ok() {
+        return Ok(());
+    }
+
+    let mut src = src;
+    let mut dest = dest;
+    src.seek(SeekFrom::Start(offset))?;
+    dest.seek(SeekFrom::Start(offset))?;
+    io::copy(&mut src.take(len), &mut dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+
+    use super::reflink_or_copy;
+    use super::reflink_or_copy_with;
+    use crate::linux;
+
+    fn file_containing(dir: &tempfile::TempDir, content: &[u8]) -> fs::File {
+        let mut file = tempfile::tempfile_in(dir).unwrap();
+        file.write_all(content).unwrap();
+        file
+    }
+
+    fn read_all(mut file: &fs::File) -> Vec<u8> {
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn copy_replaces_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = file_containing(&dir, b"hello");
+        let dest = file_containing(&dir, b"some much longer content");
+
+        reflink_or_copy(&src, &dest).unwrap();
+        assert_eq!(b"hello", read_all(&dest).as_slice());
+
+        // the copy is independent
+        (&src).write_all(b" world").unwrap();
+        assert_eq!(b"hello", read_all(&dest).as_slice());
+    }
+
+    /// Like `FICLONE`: the data is replaced, but the file is never shrunk.
+    fn fake_reflink(src: &fs::File, dest: &fs::File) -> std::io::Result<()> {
+        let data = read_all(src);
+        let mut dest = dest;
+        dest.seek(SeekFrom::Start(0))?;
+        dest.write_all(&data)
+    }
+
+    #[test]
+    fn reflink_over_longer_dest() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = file_containing(&dir, b"new");
+        let dest = file_containing(&dir, b"the old, longer, content");
+
+        reflink_or_copy_with(&src, &dest, fake_reflink, linux::copy_range).unwrap();
+        assert_eq!(b"new", read_all(&dest).as_slice());
+    }
+
+    fn unsupported<T>(_src: &fs::File, _dest: &fs::File) -> std::io::Result<T> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+    }
+
+    #[test]
+    fn copy_range_over_longer_dest() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = file_containing(&dir, b"new");
+        let dest = file_containing(&dir, b"the old, longer, content");
+
+        if linux::copy_range(&src, &dest).is_err() {
+            // no copy_file_range here
+            return;
+        }
+
+        reflink_or_copy_with(&src, &dest, unsupported, linux::copy_range).unwrap();
+        assert_eq!(b"new", read_all(&dest).as_slice());
+    }
+
+    /// Gets part-way through writing a longer file, then fails.
+    fn partial_copy_range(_src: &fs::File, dest: &fs::File) -> std::io::Result<u64> {
+        let mut dest = dest;
+        dest.seek(SeekFrom::Start(0))?;
+        dest.write_all(b"partial, and longer")?;
+        Err(std::io::Error::from(std::io::ErrorKind::Interrupted))
+    }
+
+    #[test]
+    fn userspace_copy_over_longer_dest() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = file_containing(&dir, b"new");
+        let dest = file_containing(&dir, b"the old, longer, content");
+
+        reflink_or_copy_with(&src, &dest, unsupported, unsupported).unwrap();
+        assert_eq!(b"new", read_all(&dest).as_slice());
+
+        reflink_or_copy_with(&src, &dest, unsupported, partial_copy_range).unwrap();
+        assert_eq!(b"new", read_all(&dest).as_slice());
+    }
+
+    #[test]
+    fn reflink_where_supported() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = file_containing(&dir, &[7u8; 8192]);
+        let dest = file_containing(&dir, &[9u8; 16384]);
+
+        if linux::reflink(&src, &dest).is_err() {
+            // not a reflink-capable filesystem (e.g. ext4, tmpfs)
+            return;
+        }
+
+        reflink_or_copy(&src, &dest).unwrap();
+        assert_eq!(vec![7u8; 8192], read_all(&dest));
+    }
+}
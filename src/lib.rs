@@ -15,6 +15,12 @@
 //! temp.commit().unwrap();
 //! ```
 //!
+//! Or, for the common case, [`atomic_write`]:
+//!
+//! ```rust
+//! tempfile_fast::atomic_write("example.txt", b"hello").unwrap();
+//! ```
+//!
 //! ## PersistableTempFile
 //!
 //! The raw [`PersistableTempFile`] is also available. However,
@@ -44,21 +50,201 @@ mod linux {
     use std::fs;
     use std::io;
     use std::path::Path;
+    use std::path::PathBuf;
+
+    pub const O_NOATIME: i32 = 0;
+
+    #[inline]
+    pub fn create_nonexclusive_tempfile_in<P>(
+        _dir: P,
+        _custom_flags: i32,
+        _mode: u32,
+    ) -> io::Result<fs::File> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn create_nonexclusive_tempfile_at(
+        _dir: i32,
+        _custom_flags: i32,
+        _mode: u32,
+    ) -> io::Result<fs::File> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn tmpfile_links(_file: &fs::File, _dir: &Path) -> bool {
+        true
+    }
 
     #[inline]
-    pub fn create_nonexclusive_tempfile_in<P>(_dir: P) -> io::Result<fs::File> {
-        Err(io::ErrorKind::InvalidInput.into())
+    pub fn check_custom_flags(_custom_flags: i32) -> io::Result<()> {
+        Ok(())
     }
 
     #[inline]
     pub fn link_at<P: AsRef<Path>>(_what: &fs::File, _dest: P) -> io::Result<()> {
         Err(io::ErrorKind::InvalidData.into())
     }
+
+    #[inline]
+    pub fn link_at_dir<P: AsRef<Path>>(_what: &fs::File, _dir: i32, _dest: P) -> io::Result<()> {
+        Err(io::ErrorKind::InvalidData.into())
+    }
+
+    #[inline]
+    pub fn rename_at_dir(_dir: i32, _from: &Path, _to: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn unlink_at_dir(_dir: i32, _path: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn fd_path(_fd: i32) -> io::Result<PathBuf> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "directory handles are only supported on Linux",
+        ))
+    }
+
+    #[inline]
+    pub fn same_mount(_file: &fs::File, _dir: &Path) -> io::Result<bool> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn is_overlayfs(_file: &fs::File) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    pub fn check_dir_writable(dir: &Path) -> io::Result<()> {
+        tempfile::NamedTempFile::new_in(dir).map(|_| ())
+    }
+
+    #[inline]
+    pub fn is_immutable(_path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    #[inline]
+    pub fn memfd_create(_name: &str) -> io::Result<fs::File> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn is_memfd(_file: &fs::File) -> bool {
+        false
+    }
+
+    #[inline]
+    pub fn open_flags(_file: &fs::File) -> io::Result<i32> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn rename_exchange(_a: &Path, _b: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn reflink(_src: &fs::File, _dest: &fs::File) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn clone_range(
+        _src: &fs::File,
+        _dest: &fs::File,
+        _offset: u64,
+        _len: u64,
+    ) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn punch_hole(_file: &fs::File, _offset: u64, _len: u64) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[inline]
+    pub fn copy_range(_src: &fs::File, _dest: &fs::File) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
 }
 
+mod barrier;
+mod builder;
+mod copy;
 mod persistable;
 mod sponge;
 
+pub use crate::barrier::Barrier;
+pub use crate::builder::Builder;
 pub use crate::persistable::PersistError;
+pub use crate::persistable::PersistErrorReport;
+pub use crate::persistable::PersistReport;
+pub use crate::persistable::PersistStage;
 pub use crate::persistable::PersistableTempFile;
-pub use sponge::Sponge;
+pub use crate::persistable::RenameOptions;
+pub use crate::sponge::publish_hardlink;
+pub use crate::sponge::Backend;
+pub use crate::sponge::CommitJob;
+pub use crate::sponge::CommitRecord;
+pub use crate::sponge::CommitStats;
+pub use crate::sponge::ConsumingReader;
+pub use crate::sponge::DestinationChanged;
+pub use crate::sponge::Sponge;
+pub use crate::sponge::SpongeBuilder;
+pub use crate::sponge::SpongeTee;
+pub use crate::sponge::SpongeWriter;
+
+/// Replace the contents of the file at `path` with `contents`, atomically, like
+/// [`std::fs::write`]: anyone opening `path` sees either the old file, or all of the new one.
+///
+/// This is a [`Sponge`], written to, then committed; see [`Sponge::commit`] for what happens
+/// to the file's ownership and permissions. Use [`atomic_write_durable`] if the change must
+/// survive a crash, or power loss.
+///
+/// [`Sponge`]: struct.Sponge.html
+/// [`Sponge::commit`]: struct.Sponge.html#method.commit
+pub fn atomic_write<P: AsRef<std::path::Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut sponge = Sponge::new_for(path)?;
+    sponge.write_all(contents.as_ref())?;
+    sponge.commit()
+}
+
+/// [`atomic_write`], but `fsync` the new file, and its directory, before returning, as
+/// [`Sponge::commit_durable`] does.
+///
+/// [`Sponge::commit_durable`]: struct.Sponge.html#method.commit_durable
+pub fn atomic_write_durable<P: AsRef<std::path::Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut sponge = Sponge::new_for(path)?;
+    sponge.write_all(contents.as_ref())?;
+    sponge.commit_durable()
+}
+
+/// Give the open, anonymous, file `fd` a name, `dest`, which must not already exist.
+///
+/// This is how [`PersistableTempFile`] names its `O_TMPFILE` files: `linkat()`, via
+/// `/proc/self/fd`, or, if `/proc` isn't mounted, `AT_EMPTY_PATH`, which kernels before 6.10
+/// only allow with `CAP_DAC_READ_SEARCH`. It's for callers creating their own anonymous
+/// files, e.g. with `open(O_TMPFILE)`. The file must have been created without `O_EXCL`, and
+/// on the same filesystem as `dest`. Linux refuses to link a `memfd` anywhere.
+///
+/// [`PersistableTempFile`]: enum.PersistableTempFile.html
+#[cfg(target_os = "linux")]
+pub fn link_fd(fd: std::os::fd::BorrowedFd, dest: &std::path::Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    linux::link_fd(fd.as_raw_fd(), dest)
+}
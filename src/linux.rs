@@ -3,56 +3,584 @@ extern crate libc;
 use std::ffi::CString;
 use std::fs;
 use std::io;
+use std::mem;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::FromRawFd;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::persistable::random_name;
 
 use self::libc::c_char;
+use self::libc::c_int;
+use self::libc::copy_file_range;
+use self::libc::faccessat;
+use self::libc::fallocate;
+use self::libc::fcntl;
+use self::libc::file_clone_range;
+use self::libc::ioctl;
 use self::libc::linkat;
+use self::libc::loff_t;
+use self::libc::off_t;
 use self::libc::open64 as open;
+use self::libc::openat64 as openat;
+use self::libc::statx;
+use self::libc::syscall;
+use self::libc::SYS_renameat2;
+use self::libc::AT_EACCESS;
+use self::libc::AT_EMPTY_PATH;
 use self::libc::AT_FDCWD;
 use self::libc::AT_SYMLINK_FOLLOW;
+use self::libc::AT_SYMLINK_NOFOLLOW;
+use self::libc::ENAMETOOLONG;
+use self::libc::EOPNOTSUPP;
+use self::libc::EPERM;
+use self::libc::EXDEV;
+use self::libc::FALLOC_FL_KEEP_SIZE;
+use self::libc::FALLOC_FL_PUNCH_HOLE;
+use self::libc::FD_CLOEXEC;
+use self::libc::FICLONE;
+use self::libc::FICLONERANGE;
+use self::libc::F_GETFD;
+use self::libc::F_GETFL;
+use self::libc::MFD_CLOEXEC;
+use self::libc::O_ACCMODE;
 use self::libc::O_CLOEXEC;
+use self::libc::O_CREAT;
+use self::libc::O_EXCL;
+use self::libc::O_PATH;
 use self::libc::O_RDWR;
 use self::libc::O_TMPFILE;
+use self::libc::PATH_MAX;
+use self::libc::RENAME_EXCHANGE;
+use self::libc::STATX_ATTR_APPEND;
+use self::libc::STATX_ATTR_IMMUTABLE;
+use self::libc::STATX_MNT_ID;
+use self::libc::W_OK;
+use self::libc::X_OK;
 
 pub fn link_at<P: AsRef<Path>>(what: &fs::File, dest: P) -> io::Result<()> {
-    let old_path: CString = CString::new(format!("/proc/self/fd/{}", what.as_raw_fd())).unwrap();
+    link_fd(what.as_raw_fd(), dest.as_ref())
+}
+
+/// `link_at`, for any open file descriptor.
+pub fn link_fd(fd: c_int, dest: &Path) -> io::Result<()> {
+    let new_path = cstr(dest)?;
+
+    link_fd_at(fd, AT_FDCWD, &new_path).map_err(|error| explain_too_long(error, dest))
+}
+
+/// `link_at`, but with `dest` relative to the directory `dir`.
+pub fn link_at_dir<P: AsRef<Path>>(what: &fs::File, dir: c_int, dest: P) -> io::Result<()> {
     let new_path = cstr(dest.as_ref())?;
 
-    unsafe { link_symlink_fd_at(&old_path, &new_path) }
+    link_fd_at(what.as_raw_fd(), dir, &new_path)
+        .map_err(|error| explain_too_long(error, dest.as_ref()))
+}
+
+/// Rename `from` to `to`, both relative to the directory `dir`, replacing `to`.
+pub fn rename_at_dir(dir: c_int, from: &Path, to: &Path) -> io::Result<()> {
+    let from = cstr(from)?;
+    let to = cstr(to)?;
+    if 0 != unsafe { libc::renameat(dir, from.as_ptr(), dir, to.as_ptr()) } {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Remove the name `path`, relative to the directory `dir`.
+pub fn unlink_at_dir(dir: c_int, path: &Path) -> io::Result<()> {
+    let path = cstr(path)?;
+    if 0 != unsafe { libc::unlinkat(dir, path.as_ptr(), 0) } {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Link `fd` through `/proc`, or, if `/proc` isn't mounted, with `AT_EMPTY_PATH`, which older
+/// kernels only allow with `CAP_DAC_READ_SEARCH`.
+fn link_fd_at(fd: c_int, new_dir: c_int, new_path: &CString) -> io::Result<()> {
+    match link_from(LinkSource::Proc(fd), new_dir, new_path) {
+        Err(ref e)
+            if Some(libc::ENOENT) == e.raw_os_error() && !Path::new("/proc/self/fd").exists() =>
+        {
+            link_from(LinkSource::EmptyPath(fd), new_dir, new_path)
+        }
+        other => other,
+    }
+}
+
+/// A path which refers to whatever `fd` is open on, while it remains open.
+pub fn fd_path(fd: c_int) -> io::Result<PathBuf> {
+    Ok(PathBuf::from(format!("/proc/self/fd/{}", fd)))
 }
 
 // Stolen from tempfile / std < 1.6.0.
 pub fn cstr(path: &Path) -> io::Result<CString> {
-    CString::new(path.as_os_str().as_bytes())
+    let bytes = path.as_os_str().as_bytes();
+    // `PATH_MAX` includes the terminating null
+    if bytes.len() >= PATH_MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidFilename,
+            format!(
+                "path is {} bytes, longer than the kernel accepts (PATH_MAX, {PATH_MAX}); \
+                 use a directory handle, and a shorter name relative to it: {path:?}",
+                bytes.len()
+            ),
+        ));
+    }
+
+    CString::new(bytes)
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contained a null"))
 }
 
-pub fn create_nonexclusive_tempfile_in(dir: impl AsRef<Path>) -> io::Result<fs::File> {
+/// Say which path an `ENAMETOOLONG` was about: one of its names is over `NAME_MAX`.
+fn explain_too_long(error: io::Error, path: &Path) -> io::Error {
+    if Some(ENAMETOOLONG) != error.raw_os_error() {
+        return error;
+    }
+
+    io::Error::new(
+        error.kind(),
+        format!("a name in {path:?} is too long (NAME_MAX, usually 255 bytes): {error}"),
+    )
+}
+
+pub use self::libc::O_NOATIME;
+
+/// Flags which, if added to ours, would stop the file being readable, writable, or linkable.
+const FORBIDDEN_FLAGS: c_int = O_ACCMODE | O_PATH | O_CREAT | O_EXCL;
+
+pub fn check_custom_flags(custom_flags: c_int) -> io::Result<()> {
+    if 0 != custom_flags & FORBIDDEN_FLAGS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "custom flags may not change the access mode, or include O_PATH, O_CREAT or O_EXCL",
+        ));
+    }
+    Ok(())
+}
+
+pub fn create_nonexclusive_tempfile_in(
+    dir: impl AsRef<Path>,
+    custom_flags: c_int,
+    mode: u32,
+) -> io::Result<fs::File> {
     let path: &Path = dir.as_ref();
     match unsafe {
         let path = cstr(path)?;
-        open(path.as_ptr(), O_CLOEXEC | O_TMPFILE | O_RDWR, 0o600)
+        open(
+            path.as_ptr(),
+            O_CLOEXEC | O_TMPFILE | O_RDWR | custom_flags,
+            mode,
+        )
+    } {
+        -1 => Err(tmpfile_error()),
+        fd => Ok(unsafe { FromRawFd::from_raw_fd(fd) }),
+    }
+}
+
+/// The devices which have been probed by `tmpfile_links`, and the answer.
+static PROBED: Mutex<Vec<(u64, bool)>> = Mutex::new(Vec::new());
+
+/// Whether an `O_TMPFILE` file, like `file`, created in `dir`, can actually be given a name.
+///
+/// Some network filesystems accept `O_TMPFILE`, then refuse the `linkat` at persist time. This
+/// is checked once per device, and the answer remembered. If the probe can't tell, e.g. because
+/// the directory is full, the answer is "yes", as if there had been no probe, and it's tried
+/// again next time.
+pub fn tmpfile_links(file: &fs::File, dir: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let dev = match file.metadata() {
+        Ok(metadata) => metadata.dev(),
+        Err(_) => return true,
+    };
+
+    // held while probing, so each device is only probed once
+    let mut probed = PROBED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(&(_, links)) = probed.iter().find(|(d, _)| dev == *d) {
+        return links;
+    }
+
+    match probe_links(dir) {
+        Some(links) => {
+            probed.push((dev, links));
+            links
+        }
+        None => true,
+    }
+}
+
+/// Link a throwaway `O_TMPFILE` file to a random name in `dir`, and remove it again: `None` if
+/// that failed for some reason other than the filesystem refusing.
+fn probe_links(dir: &Path) -> Option<bool> {
+    let probe = create_nonexclusive_tempfile_in(dir, 0, 0o600).ok()?;
+    match random_name(dir, &mut 0, |name| link_at(&probe, name)) {
+        Ok(name) => {
+            // a name which can't be removed is litter, but the link worked
+            let _ = fs::remove_file(name);
+            Some(true)
+        }
+        Err(ref e) if matches!(e.raw_os_error(), Some(EXDEV | EPERM | EOPNOTSUPP)) => Some(false),
+        Err(_) => None,
+    }
+}
+
+/// The error from a failed `open(O_TMPFILE)`, as [`io::ErrorKind::Unsupported`] if the problem
+/// is that the kernel, or the filesystem, doesn't support it, and the caller should fall back to
+/// a named file. Custom flags the filesystem doesn't support get the same `EINVAL`, so fall back
+/// too; any other refusal of them, e.g. `EPERM`, is a real error.
+fn tmpfile_error() -> io::Error {
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        // EISDIR: kernels before 3.11 don't know O_TMPFILE, and see only its O_DIRECTORY bit
+        Some(libc::EOPNOTSUPP) | Some(libc::EISDIR) | Some(libc::EINVAL) => io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("O_TMPFILE is not supported here: {error}"),
+        ),
+        _ => error,
+    }
+}
+
+/// `create_nonexclusive_tempfile_in`, in the already-open directory `dir`.
+pub fn create_nonexclusive_tempfile_at(
+    dir: c_int,
+    custom_flags: c_int,
+    mode: u32,
+) -> io::Result<fs::File> {
+    match unsafe {
+        openat(
+            dir,
+            c".".as_ptr(),
+            O_CLOEXEC | O_TMPFILE | O_RDWR | custom_flags,
+            mode,
+        )
     } {
-        -1 => Err(io::ErrorKind::InvalidInput.into()),
+        -1 => Err(tmpfile_error()),
+        fd => Ok(unsafe { FromRawFd::from_raw_fd(fd) }),
+    }
+}
+
+/// An anonymous file in memory, which is closed on exec.
+pub fn memfd_create(name: &str) -> io::Result<fs::File> {
+    let name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contained a null"))?;
+    match unsafe { libc::memfd_create(name.as_ptr(), MFD_CLOEXEC) } {
+        -1 => Err(io::Error::last_os_error()),
         fd => Ok(unsafe { FromRawFd::from_raw_fd(fd) }),
     }
 }
 
-/// Attempt to link an old symlink to a file back into the filesystem.
-unsafe fn link_symlink_fd_at(old_path: &CString, new_path: &CString) -> io::Result<()> {
-    if linkat(
-        AT_FDCWD,
-        old_path.as_ptr() as *const c_char,
-        AT_FDCWD,
-        new_path.as_ptr() as *const c_char,
-        AT_SYMLINK_FOLLOW,
-    ) != 0
+/// Whether `file` is from `memfd_create`, so can never be linked into a filesystem.
+pub fn is_memfd(file: &fs::File) -> bool {
+    fd_path(file.as_raw_fd())
+        .and_then(fs::read_link)
+        .map(|path| path.as_os_str().as_bytes().starts_with(b"/memfd:"))
+        .unwrap_or(false)
+}
+
+/// The flags which would re-create a file like this one: the status flags, plus `O_CLOEXEC`.
+pub fn open_flags(file: &fs::File) -> io::Result<c_int> {
+    let fd = file.as_raw_fd();
+    let status = unsafe { fcntl(fd, F_GETFL) };
+    if -1 == status {
+        return Err(io::Error::last_os_error());
+    }
+    let descriptor = unsafe { fcntl(fd, F_GETFD) };
+    if -1 == descriptor {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(if 0 != descriptor & FD_CLOEXEC {
+        status | O_CLOEXEC
+    } else {
+        status
+    })
+}
+
+/// How `linkat` is told which open file to link, which decides the flags it needs.
+#[derive(Copy, Clone)]
+enum LinkSource {
+    /// `/proc/self/fd/N`: a (magic) symlink to the file, which must be followed
+    /// (`AT_SYMLINK_FOLLOW`), or the symlink itself would be linked.
+    Proc(c_int),
+    /// The descriptor itself, and an empty path (`AT_EMPTY_PATH`): there's no symlink, so
+    /// nothing to follow.
+    EmptyPath(c_int),
+}
+
+/// Link the open file `source` back into the filesystem, as `new_path`, relative to `new_dir`.
+fn link_from(source: LinkSource, new_dir: c_int, new_path: &CString) -> io::Result<()> {
+    let proc_path;
+    let (old_dir, old_path, flags) = match source {
+        LinkSource::Proc(fd) => {
+            proc_path = CString::new(format!("/proc/self/fd/{}", fd)).unwrap();
+            (AT_FDCWD, proc_path.as_c_str(), AT_SYMLINK_FOLLOW)
+        }
+        LinkSource::EmptyPath(fd) => (fd, c"", AT_EMPTY_PATH),
+    };
+
+    if unsafe {
+        linkat(
+            old_dir,
+            old_path.as_ptr() as *const c_char,
+            new_dir,
+            new_path.as_ptr() as *const c_char,
+            flags,
+        )
+    } != 0
     {
         Err(io::Error::last_os_error())
     } else {
         Ok(())
     }
 }
+
+/// Whether `file` is on the same mount as the directory `dir`, which `linkat` requires.
+pub fn same_mount(file: &fs::File, dir: &Path) -> io::Result<bool> {
+    let dir = fs::File::open(dir)?;
+    Ok(mount_of(file.as_raw_fd())? == mount_of(dir.as_raw_fd())?)
+}
+
+/// Where an open file is mounted: its mount id (Linux 5.8+), or, failing that, its device.
+#[derive(PartialEq)]
+enum Mount {
+    Id(u64),
+    Device(u32, u32),
+}
+
+fn mount_of(fd: c_int) -> io::Result<Mount> {
+    let mut buf: statx = unsafe { mem::zeroed() };
+    if 0 != unsafe { libc::statx(fd, c"".as_ptr(), AT_EMPTY_PATH, STATX_MNT_ID, &mut buf) } {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(if 0 != buf.stx_mask & STATX_MNT_ID {
+        Mount::Id(buf.stx_mnt_id)
+    } else {
+        Mount::Device(buf.stx_dev_major, buf.stx_dev_minor)
+    })
+}
+
+/// Whether `file` is on an overlay filesystem (overlayfs), as used by containers.
+pub fn is_overlayfs(file: &fs::File) -> io::Result<bool> {
+    let mut buf: libc::statfs = unsafe { mem::zeroed() };
+    if 0 != unsafe { libc::fstatfs(file.as_raw_fd(), &mut buf) } {
+        return Err(io::Error::last_os_error());
+    }
+    // `f_type`'s type varies by architecture
+    Ok(buf.f_type as u64 == libc::OVERLAYFS_SUPER_MAGIC as u64)
+}
+
+/// Check that the process may create and remove names in `dir`, by its effective ids; this
+/// also fails on a read-only filesystem.
+pub fn check_dir_writable(dir: &Path) -> io::Result<()> {
+    let path = cstr(dir)?;
+    if 0 != unsafe { faccessat(AT_FDCWD, path.as_ptr(), W_OK | X_OK, AT_EACCESS) } {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `path` (not following symlinks) is immutable or append-only (`chattr +i`, `+a`),
+/// so it can't be replaced, even by root.
+pub fn is_immutable(path: &Path) -> io::Result<bool> {
+    let path = cstr(path)?;
+    let mut buf: statx = unsafe { mem::zeroed() };
+    if 0 != unsafe { libc::statx(AT_FDCWD, path.as_ptr(), AT_SYMLINK_NOFOLLOW, 0, &mut buf) } {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = (STATX_ATTR_IMMUTABLE | STATX_ATTR_APPEND) as u64;
+    Ok(0 != buf.stx_attributes_mask & buf.stx_attributes & flags)
+}
+
+/// Make `dest` a copy-on-write clone of all of `src`, if the filesystem supports it (btrfs, xfs).
+pub fn reflink(src: &fs::File, dest: &fs::File) -> io::Result<()> {
+    if unsafe { ioctl(dest.as_raw_fd(), FICLONE, src.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Make `len` bytes of `dest`, at `offset`, a copy-on-write clone of the same range of `src`.
+pub fn clone_range(src: &fs::File, dest: &fs::File, offset: u64, len: u64) -> io::Result<()> {
+    let range = file_clone_range {
+        src_fd: src.as_raw_fd().into(),
+        src_offset: offset,
+        src_length: len,
+        dest_offset: offset,
+    };
+    if unsafe { ioctl(dest.as_raw_fd(), FICLONERANGE, &range) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Release the storage for `len` bytes of `file`, at `offset`, which then read as zeros,
+/// without changing its length.
+pub fn punch_hole(file: &fs::File, offset: u64, len: u64) -> io::Result<()> {
+    let mode = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+    if 0 != unsafe { fallocate(file.as_raw_fd(), mode, offset as off_t, len as off_t) } {
+        let error = io::Error::last_os_error();
+        return Err(match error.raw_os_error() {
+            Some(libc::EOPNOTSUPP) => io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("punching holes is not supported here: {error}"),
+            ),
+            _ => error,
+        });
+    }
+    Ok(())
+}
+
+/// Copy all of `src` to the start of `dest`, inside the kernel. File positions are not used.
+pub fn copy_range(src: &fs::File, dest: &fs::File) -> io::Result<u64> {
+    let mut off_in: loff_t = 0;
+    let mut off_out: loff_t = 0;
+    loop {
+        match unsafe {
+            copy_file_range(
+                src.as_raw_fd(),
+                &mut off_in,
+                dest.as_raw_fd(),
+                &mut off_out,
+                1 << 30,
+                0,
+            )
+        } {
+            -1 => {
+                let error = io::Error::last_os_error();
+                if io::ErrorKind::Interrupted != error.kind() {
+                    return Err(error);
+                }
+            }
+            0 => return Ok(off_out as u64),
+            _ => (),
+        }
+    }
+}
+
+/// Atomically exchange two existing paths, with `renameat2(RENAME_EXCHANGE)`.
+pub fn rename_exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let a = cstr(a)?;
+    let b = cstr(b)?;
+    if 0 != unsafe {
+        syscall(
+            SYS_renameat2,
+            AT_FDCWD,
+            a.as_ptr(),
+            AT_FDCWD,
+            b.as_ptr(),
+            RENAME_EXCHANGE,
+        )
+    } {
+        let error = io::Error::last_os_error();
+        return Err(match error.raw_os_error() {
+            Some(libc::EINVAL) | Some(libc::ENOSYS) => io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("atomic exchange is not supported here: {error}"),
+            ),
+            _ => error,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    use super::create_nonexclusive_tempfile_in;
+    use super::cstr;
+    use super::link_from;
+    use super::probe_links;
+    use super::LinkSource;
+    use super::AT_FDCWD;
+
+    /// Both ways of naming a file must give a regular file, not a link to `/proc`.
+    fn links_regular_file(source: fn(i32) -> LinkSource) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = match create_nonexclusive_tempfile_in(dir.path(), 0, 0o600) {
+            Ok(file) => file,
+            // no `O_TMPFILE` here
+            Err(_) => return,
+        };
+        file.write_all(b"linked").unwrap();
+
+        let dest = dir.path().join("dest");
+        let source = source(file.as_raw_fd());
+        match link_from(source, AT_FDCWD, &cstr(&dest).unwrap()) {
+            Ok(()) => (),
+            // `AT_EMPTY_PATH` without `CAP_DAC_READ_SEARCH`, on a kernel before 6.10
+            Err(ref e)
+                if matches!(source, LinkSource::EmptyPath(_))
+                    && Some(libc::ENOENT) == e.raw_os_error() =>
+            {
+                return
+            }
+            Err(e) => panic!("linking failed: {e}"),
+        }
+
+        let metadata = fs::symlink_metadata(&dest).unwrap();
+        assert!(metadata.file_type().is_file());
+        assert_eq!(1, metadata.nlink());
+        assert_eq!(b"linked", fs::read(&dest).unwrap().as_slice());
+    }
+
+    #[test]
+    fn proc_link_is_regular_file() {
+        links_regular_file(LinkSource::Proc);
+    }
+
+    #[test]
+    fn empty_path_link_is_regular_file() {
+        links_regular_file(LinkSource::EmptyPath);
+    }
+
+    #[test]
+    fn probe_links_here() {
+        let dir = tempfile::tempdir().unwrap();
+        if create_nonexclusive_tempfile_in(dir.path(), 0, 0o600).is_err() {
+            // no `O_TMPFILE` here
+            return;
+        }
+
+        assert_eq!(Some(true), probe_links(dir.path()));
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    /// A directory which allows linking, but not removing, still links.
+    #[test]
+    fn probe_links_append_only() {
+        use std::process::Command;
+
+        let chattr = |flag: &str, path: &std::path::Path| {
+            Command::new("chattr")
+                .arg(flag)
+                .arg(path)
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        if create_nonexclusive_tempfile_in(dir.path(), 0, 0o600).is_err()
+            || !chattr("+a", dir.path())
+        {
+            return;
+        }
+
+        let probed = probe_links(dir.path());
+        let created = crate::Builder::new()
+            .probe_link(true)
+            .tempfile_in(dir.path())
+            .map(|file| matches!(file, crate::PersistableTempFile::Linux(_)));
+        assert!(chattr("-a", dir.path()));
+
+        assert_eq!(Some(true), probed);
+        assert!(created.unwrap());
+    }
+}
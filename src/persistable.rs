@@ -1,3 +1,4 @@
+use std::ffi::OsString;
 use std::fmt;
 use std::fs;
 use std::io;
@@ -7,11 +8,17 @@ use std::io::SeekFrom;
 use std::io::Write;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::path::Component;
 use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
 
 use rand::RngCore;
 
+use crate::copy;
 use crate::linux;
+use crate::Builder;
 
 /// An abstraction over different platform-specific temporary file optimisations.
 pub enum PersistableTempFile {
@@ -27,12 +34,253 @@ impl PersistableTempFile {
     /// [`tempfile::NamedTempFile`].
     ///
     /// [`tempfile::NamedTempFile`]: https://docs.rs/tempfile/*/tempfile/struct.NamedTempFile.html
+    ///
+    /// See [`Builder`] for more options.
+    ///
+    /// [`Builder`]: struct.Builder.html
     pub fn new_in<P: AsRef<Path>>(dir: P) -> io::Result<PersistableTempFile> {
-        if let Ok(file) = linux::create_nonexclusive_tempfile_in(&dir) {
-            return Ok(Linux(file));
+        Builder::new().tempfile_in(dir)
+    }
+
+    /// [`new_in`](#method.new_in), but, if the `Fallback` is needed, create it with an
+    /// existing, configured, [`tempfile::Builder`], so its prefix, suffix, random length and
+    /// permissions are used.
+    ///
+    /// The `Linux` fast-path file has no name, so the name options can't apply to it. Neither
+    /// can the permissions, unfortunately, as a `tempfile::Builder` doesn't reveal them: the
+    /// file is created with the default `0o600`. Use [`Builder::temp_mode`] (which applies to
+    /// both) if that matters.
+    ///
+    /// [`tempfile::Builder`]: https://docs.rs/tempfile/*/tempfile/struct.Builder.html
+    /// [`Builder::temp_mode`]: struct.Builder.html#method.temp_mode
+    pub fn new_in_with_builder<P: AsRef<Path>>(
+        dir: P,
+        builder: &tempfile::Builder,
+    ) -> io::Result<PersistableTempFile> {
+        Builder::new().tempfile_in_with(dir, builder)
+    }
+
+    /// [`new_in`](#method.new_in), but never fall back to a named temporary file: only the
+    /// `Linux` fast path's anonymous file, which no one else can find, or open, is acceptable.
+    ///
+    /// Where that isn't available (on other platforms, older kernels, or filesystems without
+    /// `O_TMPFILE`), this fails with [`io::ErrorKind::Unsupported`]. A file returned by this is
+    /// always the `Linux` variant, so no guessable name for it ever exists, until it's
+    /// persisted. Persisting it may still use an intermediate name, next to the destination;
+    /// see [`persist_by_rename`](#method.persist_by_rename).
+    pub fn new_in_exclusive<P: AsRef<Path>>(dir: P) -> io::Result<PersistableTempFile> {
+        Builder::new().tempfile_in_exclusive(dir)
+    }
+
+    /// Create a temporary file in an already-open directory.
+    ///
+    /// This is `Builder::new().tempfile_in_dir_fd(dir)`; see
+    /// [`Builder::tempfile_in_dir_fd`](struct.Builder.html#method.tempfile_in_dir_fd).
+    #[cfg(unix)]
+    pub fn new_in_dir_fd(dir: std::os::fd::BorrowedFd) -> io::Result<PersistableTempFile> {
+        Builder::new().tempfile_in_dir_fd(dir)
+    }
+
+    /// Create a temporary file in memory, with `memfd_create`, which is only written to disk when
+    /// it is persisted. `name` is only for debugging: it appears in `/proc/self/fd`.
+    ///
+    /// The data uses RAM (or swap) until then, however large it gets, regardless of where it
+    /// will eventually be stored. A memfd can't be linked into any real filesystem, so
+    /// persisting always falls back to copying the data into a new file next to the
+    /// destination, then renaming that into place, which needs the disk space for the whole file
+    /// at that point.
+    ///
+    /// Only supported on Linux (3.17+); elsewhere, this fails with
+    /// [`io::ErrorKind::Unsupported`].
+    pub fn new_memfd(name: &str) -> io::Result<PersistableTempFile> {
+        linux::memfd_create(name).map(Linux)
+    }
+
+    /// Adopt an anonymous file created elsewhere, e.g. with `open(O_TMPFILE)` and your own flags,
+    /// as the `Linux` variant, so it can be persisted like one created by this crate.
+    ///
+    /// For the persist to work, `fd` must be a writable regular file, with no name, which may
+    /// be linked: one from `O_TMPFILE` without `O_EXCL`. Anything else will fail to persist.
+    /// A `memfd_create` file can't be linked.
+    ///
+    /// # Safety
+    ///
+    /// The `PersistableTempFile` takes ownership of `fd`, which must be open, and not owned by
+    /// anything else: it will be closed when the `PersistableTempFile` is dropped.
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd_linux(fd: std::os::fd::RawFd) -> PersistableTempFile {
+        use std::os::fd::FromRawFd;
+        Linux(fs::File::from_raw_fd(fd))
+    }
+
+    /// Atomically exchange two existing files (or directories): afterwards, `a` has `b`'s
+    /// old content, and `b` has `a`'s, and there is no point at which either path is missing.
+    ///
+    /// This doesn't involve a temporary file at all, but is useful alongside them, e.g. for
+    /// switching between an active and a standby file.
+    ///
+    /// Only supported on Linux (3.15+), with `renameat2(RENAME_EXCHANGE)`, and only on
+    /// filesystems which support it; elsewhere, this fails with
+    /// [`io::ErrorKind::Unsupported`]. Both paths must be on the same filesystem.
+    pub fn swap<A: AsRef<Path>, B: AsRef<Path>>(a: A, b: B) -> io::Result<()> {
+        linux::rename_exchange(a.as_ref(), b.as_ref())
+    }
+
+    /// The current position in the file, where the next write will go, like
+    /// [`Seek::stream_position`], but without needing `Seek` imported.
+    pub fn stream_position(&mut self) -> io::Result<u64> {
+        self.as_mut().stream_position()
+    }
+
+    /// The filesystem's preferred size for I/O on this file (`st_blksize`), e.g. for sizing
+    /// buffers, or aligning `O_DIRECT` writes.
+    ///
+    /// Only available on `unix`; elsewhere, this fails with [`io::ErrorKind::Unsupported`].
+    pub fn io_block_size(&self) -> io::Result<u64> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            Ok(self.as_ref().metadata()?.blksize())
+        }
+
+        #[cfg(not(unix))]
+        {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    /// The number of names the file has (`st_nlink`): `0` for the `Linux` fast path, until it's
+    /// persisted, which is what makes it impossible for anyone else to find; `1` for the
+    /// `Fallback`.
+    ///
+    /// Only available on `unix`; elsewhere, this fails with [`io::ErrorKind::Unsupported`].
+    pub fn link_count(&self) -> io::Result<u64> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            Ok(self.as_ref().metadata()?.nlink())
+        }
+
+        #[cfg(not(unix))]
+        {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    /// Release the storage for `len` bytes of the file, starting at `offset`, leaving a hole,
+    /// which reads as zeros, e.g. for a disk image, instead of writing out runs of zeros. The
+    /// file's length is unchanged, and the file position isn't used or moved.
+    ///
+    /// This is `fallocate(FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE)`, for either variant. It
+    /// fails with [`io::ErrorKind::Unsupported`] if the filesystem can't do it, and on other
+    /// platforms.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        linux::punch_hole(self.as_ref(), offset, len)
+    }
+
+    /// Throw away this temporary file, reporting any error from closing it.
+    ///
+    /// Dropping a `PersistableTempFile` does the same thing, but ignores errors. `close()` can
+    /// report errors, such as `EIO`, from earlier writes which the device only failed later;
+    /// this is the only way to observe them.
+    ///
+    /// For the `Fallback`, the named file is also removed, and any error from that is reported.
+    /// On non-`unix` platforms, errors from closing the file can't be observed.
+    pub fn discard(self) -> io::Result<()> {
+        match self {
+            Linux(file) => close(file),
+            Fallback(named) => {
+                let (file, path) = named.into_parts();
+                let closed = close(file);
+                let removed = path.close();
+                closed.and(removed)
+            }
+        }
+    }
+
+    /// Whether this file could be given a name in `dir`, without trying it, so without using
+    /// up the file if it can't.
+    ///
+    /// For the `Linux` fast path, `linkat` needs `dir` to be on the same mount as the
+    /// directory the file was created in (not just the same filesystem: a bind mount of the
+    /// same filesystem won't do). A file from [`new_memfd`](#method.new_memfd) can never be
+    /// linked, so this is always `false` for it. For the `Fallback`, this is whether its name
+    /// can be renamed into `dir`: whether they're on the same filesystem.
+    ///
+    /// This doesn't try a link: an anonymous file which has been given a name, which is then
+    /// removed, can never be linked again. So `true` means the link is expected to work, but it
+    /// can still fail for other reasons, such as permissions, or a full directory, e.g. to
+    /// decide whether to ask for [`RenameOptions::copy_across_filesystems`].
+    pub fn can_link_to_dir<P: AsRef<Path>>(&self, dir: P) -> io::Result<bool> {
+        match *self {
+            Linux(ref file) => linux::same_mount(file, dir.as_ref()),
+            Fallback(ref named) => match named.path().parent() {
+                Some(parent) => same_filesystem(parent, dir.as_ref()),
+                None => Ok(false),
+            },
+        }
+    }
+
+    /// Stop treating this as a temporary file, and hand back the file, and its name, if it has
+    /// one, without persisting it anywhere.
+    ///
+    /// For the `Fallback`, this is [`tempfile::NamedTempFile::keep`]: the file stays where it
+    /// was created, under its random name, and won't be deleted on drop.
+    ///
+    /// The `Linux` fast-path file has no name, so the path is `None`. Nothing else about it
+    /// changes: it still disappears when the last handle is closed, unless it is linked
+    /// somewhere first. Use [`with_name`](#method.with_name) beforehand to give it a name.
+    ///
+    /// [`tempfile::NamedTempFile::keep`]: https://docs.rs/tempfile/*/tempfile/struct.NamedTempFile.html#method.keep
+    pub fn keep(self) -> io::Result<(fs::File, Option<PathBuf>)> {
+        match self {
+            Linux(file) => Ok((file, None)),
+            Fallback(named) => {
+                let (file, path) = named.keep().map_err(|e| e.error)?;
+                Ok((file, Some(path)))
+            }
+        }
+    }
+
+    /// Call `f` with a path to this temporary file, for APIs which can't take an open file.
+    ///
+    /// The `Linux` fast-path file has no name, so one is made: the file is linked into `dir`,
+    /// under a random hidden name, which `f` is called with. `dir` must be on the same
+    /// filesystem as the file. Anything `f` writes through the path is written to this file,
+    /// but flush any buffered writes of your own first.
+    ///
+    /// Once an anonymous file has had a name, and it has been removed, Linux refuses to link it
+    /// anywhere again, so it could never be persisted. The name is therefore kept: the file
+    /// becomes a `Fallback`, which is removed on drop, and persisted by renaming, like any other.
+    /// Later calls reuse the name.
+    ///
+    /// For the `Fallback`, `f` is called with the existing name, and `dir` is ignored.
+    pub fn with_name<P: AsRef<Path>, F, T>(&mut self, dir: P, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&Path) -> T,
+    {
+        if let Linux(ref file) = *self {
+            *self = Fallback(name_in(file, dir.as_ref())?);
+        }
+
+        match *self {
+            Fallback(ref named) => Ok(f(named.path())),
+            Linux(_) => unreachable!("a name was just created"),
         }
+    }
 
-        Ok(Fallback(tempfile::Builder::new().tempfile_in(dir)?))
+    /// The `open()` flags of the `Linux` fast-path file, as reported by the kernel, plus
+    /// `O_CLOEXEC` if it is set. `None` for the `Fallback`.
+    ///
+    /// These can be used to create a similar file in another process, e.g. by opening a
+    /// directory (or `/proc/self/fd/...`) with them. They are informational: the exact flags the
+    /// crate uses are subject to change, and the kernel may report flags which weren't requested,
+    /// such as `O_LARGEFILE`.
+    pub fn open_flags(&self) -> Option<i32> {
+        match *self {
+            Linux(ref file) => linux::open_flags(file).ok(),
+            Fallback(_) => None,
+        }
     }
 }
 
@@ -106,13 +354,13 @@ impl Seek for PersistableTempFile {
     }
 }
 
-impl<'a> Read for &'a PersistableTempFile {
+impl Read for &PersistableTempFile {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.as_ref().read(buf)
     }
 }
 
-impl<'a> Write for &'a PersistableTempFile {
+impl Write for &PersistableTempFile {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.as_ref().write(buf)
     }
@@ -122,7 +370,7 @@ impl<'a> Write for &'a PersistableTempFile {
     }
 }
 
-impl<'a> Seek for &'a PersistableTempFile {
+impl Seek for &PersistableTempFile {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         self.as_ref().seek(pos)
     }
@@ -144,59 +392,618 @@ impl ::std::os::windows::io::AsRawHandle for PersistableTempFile {
     }
 }
 
+/// Persist an unlinkable `temp` (e.g. on another filesystem) by copying it into a new named
+/// temporary file, next to `dest`, then persisting that, keeping `temp`'s ownership and
+/// permissions. Returns the copy, open for writing.
+fn persist_by_copy(
+    temp: PersistableTempFile,
+    dest: &Path,
+    overwrite: bool,
+) -> Result<fs::File, PersistError> {
+    let dir = match dest.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let failed = |error, file, stage| PersistError {
+        error,
+        file,
+        stage,
+        dest: None,
+    };
+
+    let named = match tempfile::NamedTempFile::new_in(dir)
+        .and_then(|named| copy_to(temp.as_ref(), named.as_file()).map(|()| named))
+    {
+        Ok(named) => named,
+        Err(error) => return Err(failed(error, temp, PersistStage::Copy)),
+    };
+
+    let persisted = if overwrite {
+        named
+            .persist(dest)
+            .map_err(|e| (e.error, PersistStage::Rename))
+    } else {
+        named
+            .persist_noclobber(dest)
+            .map_err(|e| (e.error, PersistStage::Link))
+    };
+
+    persisted.map_err(|(error, stage)| failed(error, temp, stage))
+}
+
+/// `fsync` the directory containing `path`, so entries just created in it are on disk.
+#[cfg(unix)]
+pub(crate) fn sync_dir_of(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Windows can't open a directory as a file, and has no equivalent.
+#[cfg(not(unix))]
+pub(crate) fn sync_dir_of(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Copy the data, then the ownership and permissions, from `src` to `dest`.
+fn copy_to(src: &fs::File, dest: &fs::File) -> io::Result<()> {
+    copy::reflink_or_copy(src, dest)?;
+
+    let metadata = src.metadata()?;
+
+    // ownership first, as changing it can clear the set-id bits
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let current = dest.metadata()?;
+        if current.uid() != metadata.uid() || current.gid() != metadata.gid() {
+            std::os::unix::fs::fchown(dest, Some(metadata.uid()), Some(metadata.gid()))?;
+        }
+    }
+
+    dest.set_permissions(metadata.permissions())
+}
+
+/// Link `file` into `dir` under a new, random, hidden name, and return that name.
+/// `attempts` is increased by the number of names tried.
+fn link_random_name(file: &fs::File, dir: &Path, attempts: &mut u32) -> io::Result<PathBuf> {
+    random_name(dir, attempts, |name| linux::link_at(file, name))
+}
+
+/// Call `create` with new, random, hidden names in `dir` until one isn't already taken, and
+/// return that name. `attempts` is increased by the number of names tried.
+pub(crate) fn random_name<F>(dir: &Path, attempts: &mut u32, create: F) -> io::Result<PathBuf>
+where
+    F: FnMut(&Path) -> io::Result<()>,
+{
+    random_name_with(dir, attempts, &mut default_name, create)
+}
+
+/// The usual intermediate name: hidden, and obviously temporary.
+fn default_name(random: u64) -> OsString {
+    format!(".{:x}.tmp", random).into()
+}
+
+/// `random_name`, with the names made by `name`, from a random number.
+fn random_name_with<F>(
+    dir: &Path,
+    attempts: &mut u32,
+    name: &mut dyn FnMut(u64) -> OsString,
+    mut create: F,
+) -> io::Result<PathBuf>
+where
+    F: FnMut(&Path) -> io::Result<()>,
+{
+    let mut rng = ::rand::thread_rng();
+
+    for _ in 0..32768 {
+        *attempts += 1;
+        let name = name(rng.next_u64());
+        let mut components = Path::new(&name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("temporary file name must be a plain file name, not {name:?}"),
+            ));
+        }
+        let name = dir.join(name);
+
+        match create(&name) {
+            Ok(()) => return Ok(name),
+            Err(error) => {
+                if io::ErrorKind::AlreadyExists != error.kind() {
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    Err(io::Error::other("couldn't create temporary file"))
+}
+
+/// Whether a failed rename might work if tried again.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ResourceBusy
+    )
+}
+
+/// Whether `a` and `b` are on the same filesystem, so a name can be renamed from one to the other.
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(a.metadata()?.dev() == b.metadata()?.dev())
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(_a: &Path, _b: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Give `file` a random name in `dir`, and manage it as a `NamedTempFile` from now on.
+pub(crate) fn name_in(file: &fs::File, dir: &Path) -> io::Result<tempfile::NamedTempFile> {
+    let name = link_random_name(file, dir, &mut 0)?;
+    match file.try_clone() {
+        // `try_from_path` would need a much newer `tempfile`, for no benefit here
+        #[allow(deprecated)]
+        Ok(file) => Ok(tempfile::NamedTempFile::from_parts(
+            file,
+            tempfile::TempPath::from_path(name),
+        )),
+        Err(error) => {
+            let _ = fs::remove_file(&name);
+            Err(error)
+        }
+    }
+}
+
+#[cfg(unix)]
+fn close(file: fs::File) -> io::Result<()> {
+    use std::os::unix::io::IntoRawFd;
+
+    // Don't retry on EINTR: Linux has released the fd regardless, and it may already be reused.
+    if 0 != unsafe { libc::close(file.into_raw_fd()) } {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn close(file: fs::File) -> io::Result<()> {
+    drop(file);
+    Ok(())
+}
+
 /// Error returned when persisting a temporary file fails.
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct PersistError {
     /// The underlying IO error.
     pub error: io::Error,
     /// The temporary file that couldn't be persisted.
     pub file: PersistableTempFile,
+    /// What was being attempted when the error happened.
+    pub stage: PersistStage,
+    pub(crate) dest: Option<PathBuf>,
+}
+
+/// The step of persisting a temporary file which failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PersistStage {
+    /// Flushing the written data to the temporary file failed, so the data may be incomplete.
+    Flush,
+    /// Creating a name for the temporary file, directly at the destination, failed.
+    Link,
+    /// Creating an intermediate name for the temporary file, next to the destination, failed.
+    TempCreate,
+    /// Renaming the intermediate name over the destination failed. The intermediate name has
+    /// been removed again.
+    Rename,
+    /// Renaming the intermediate name over the destination failed (this is the `error`), *and*
+    /// removing the intermediate name failed, so the data is still on disk, under a temporary
+    /// name, next to the destination.
+    Cleanup,
+    /// Copying the data into the destination failed.
+    Copy,
+    /// Syncing the temporary file's data to stable storage failed, before it was persisted.
+    Sync,
+    /// The file was persisted, but syncing the destination's directory failed, so the new name
+    /// might not survive a crash. `file` is a handle to the persisted file, not a temporary one:
+    /// it's already at the destination, and persisting it again would give it a second name.
+    SyncDir,
+}
+
+/// Details of a successful [`PersistableTempFile::persist_by_rename_with`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PersistReport {
+    /// The number of intermediate names which were already taken, and had to be tried again.
+    /// Always `0` for the `Fallback`, which leaves this to `tempfile`.
+    pub retries: u32,
+}
+
+/// Options for [`PersistableTempFile::persist_by_rename_with`].
+#[derive(Default)]
+pub struct RenameOptions {
+    staging_dir: Option<PathBuf>,
+    name: Option<Box<dyn FnMut(u64) -> OsString + Send>>,
+    rename_attempts: u32,
+    backoff: Duration,
+    copy_across_filesystems: bool,
+}
+
+impl fmt::Debug for RenameOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RenameOptions")
+            .field("staging_dir", &self.staging_dir)
+            .field("custom_name", &self.name.is_some())
+            .field("rename_attempts", &self.rename_attempts)
+            .field("backoff", &self.backoff)
+            .field("copy_across_filesystems", &self.copy_across_filesystems)
+            .finish()
+    }
+}
+
+impl RenameOptions {
+    /// The default options: the same as [`PersistableTempFile::persist_by_rename`].
+    pub fn new() -> RenameOptions {
+        RenameOptions::default()
+    }
+
+    /// Create the intermediate name in `dir`, instead of next to the destination, so the
+    /// destination's directory only ever sees the final rename. Default: the destination's
+    /// directory.
+    ///
+    /// This is for directories watched by tools which react to every new entry, even hidden
+    /// ones. `dir` should be a (hidden) directory on the same filesystem as the destination,
+    /// e.g. a `.tmp/` next to it. If it's on a different filesystem, where it can't be renamed
+    /// from, the destination's directory is used after all.
+    pub fn staging_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut RenameOptions {
+        self.staging_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Make the intermediate names with `name`, from a random number, instead of the default,
+    /// `.{random:x}.tmp`, e.g. to match a directory watcher's ignore pattern:
+    ///
+    /// ```rust
+    /// let mut options = tempfile_fast::RenameOptions::new();
+    /// // Emacs-style lock file names, which many tools ignore
+    /// options.name(|random| format!(".#{:x}", random).into());
+    /// ```
+    ///
+    /// `name` must return a file name, not a path; it's created in the destination's directory
+    /// (or the [`staging_dir`](#method.staging_dir)). Anything else, such as an absolute path,
+    /// `..`, or a name containing a separator, fails the persist with
+    /// [`io::ErrorKind::InvalidInput`]. If the name is already taken, `name` is
+    /// called again, with a new random number, so the names must depend on it, or every retry
+    /// will collide.
+    pub fn name<F>(&mut self, name: F) -> &mut RenameOptions
+    where
+        F: FnMut(u64) -> OsString + Send + 'static,
+    {
+        self.name = Some(Box::new(name));
+        self
+    }
+
+    /// Try the final rename over the destination up to `attempts` times, waiting `backoff`
+    /// before the second attempt, and twice as long before each one after that, if it fails
+    /// with an error which may be temporary: [`io::ErrorKind::PermissionDenied`] or
+    /// [`io::ErrorKind::ResourceBusy`].
+    ///
+    /// Network filesystems (SMB/CIFS) can briefly refuse to rename over a file which someone
+    /// has open. Other errors are returned immediately. Default: a single attempt.
+    pub fn rename_attempts(&mut self, attempts: u32, backoff: Duration) -> &mut RenameOptions {
+        self.rename_attempts = attempts;
+        self.backoff = backoff;
+        self
+    }
+
+    /// If the destination is on a different filesystem, copy the data into a new temporary file
+    /// next to it, and rename that into place, instead of failing with
+    /// [`io::ErrorKind::CrossesDevices`]. The copy keeps the file's ownership and permissions.
+    ///
+    /// This is always done for a file from
+    /// [`new_memfd`](PersistableTempFile::new_memfd), which can't be linked anywhere. Default:
+    /// `false`.
+    pub fn copy_across_filesystems(&mut self, copy: bool) -> &mut RenameOptions {
+        self.copy_across_filesystems = copy;
+        self
+    }
+
+    /// Run `rename`, trying again as configured by `rename_attempts`.
+    fn retry_rename<F>(&self, mut rename: F) -> io::Result<()>
+    where
+        F: FnMut() -> io::Result<()>,
+    {
+        let mut delay = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match rename() {
+                Err(ref e) if attempt < self.rename_attempts && is_transient(e) => {
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// What happened during a persist, for reporting.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct PersistStats {
+    /// The file was linked straight to the destination, without an intermediate name.
+    pub linked_directly: bool,
+    /// The number of intermediate names tried.
+    pub attempts: u32,
+    /// The file couldn't be linked, so was copied.
+    pub copied: bool,
+    /// An existing destination was replaced in place, instead of being renamed over.
+    pub replaced: bool,
 }
 
 impl PersistError {
-    fn new(error: io::Error, file: fs::File) -> PersistError {
+    fn new(error: io::Error, file: fs::File, stage: PersistStage) -> PersistError {
         PersistError {
             error,
             file: PersistableTempFile::Linux(file),
+            stage,
+            dest: None,
         }
     }
-}
 
-impl From<tempfile::PersistError> for PersistError {
-    fn from(e: tempfile::PersistError) -> Self {
+    fn fallback(e: tempfile::PersistError, stage: PersistStage) -> PersistError {
         PersistError {
             error: e.error,
             file: PersistableTempFile::Fallback(e.file),
+            stage,
+            dest: None,
         }
     }
 }
 
+impl PersistError {
+    /// The destination, handed back, if it was given as an owned `PathBuf`, to
+    /// [`PersistableTempFile::persist_by_rename_path`]. `None` otherwise.
+    pub fn dest(&self) -> Option<&Path> {
+        self.dest.as_deref()
+    }
+
+    /// Take the destination handed back by [`PersistableTempFile::persist_by_rename_path`],
+    /// leaving `None`.
+    pub fn take_dest(&mut self) -> Option<PathBuf> {
+        self.dest.take()
+    }
+
+    /// A summary of this error, without the file, which can be cloned, e.g. for logging
+    /// frameworks which need that, while this error is kept to recover the file.
+    pub fn to_report(&self) -> PersistErrorReport {
+        PersistErrorReport {
+            kind: self.error.kind(),
+            message: self.error.to_string(),
+            stage: self.stage,
+            dest: self.dest.clone(),
+        }
+    }
+}
+
+/// A [`PersistError`], without the file: see [`PersistError::to_report`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PersistErrorReport {
+    /// The kind of the underlying IO error.
+    pub kind: io::ErrorKind,
+    /// The underlying IO error, as text.
+    pub message: String,
+    /// What was being attempted when the error happened.
+    pub stage: PersistStage,
+    /// The destination, if the error had it.
+    pub dest: Option<PathBuf>,
+}
+
+impl fmt::Display for PersistErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "persisting failed at {:?}: {}", self.stage, self.message)
+    }
+}
+
+impl std::error::Error for PersistErrorReport {}
+
+/// The conversion can't know which stage failed, and assumes [`PersistStage::Rename`].
+impl From<tempfile::PersistError> for PersistError {
+    fn from(e: tempfile::PersistError) -> Self {
+        PersistError::fallback(e, PersistStage::Rename)
+    }
+}
+
 impl PersistableTempFile {
     /// Store this temporary file into a real file path.
     ///
-    /// The path must not exist and must be on the same mounted filesystem.
+    /// The path must not exist and must be on the same mounted filesystem; if it isn't, this
+    /// fails with [`io::ErrorKind::CrossesDevices`]. A file from
+    /// [`new_memfd`](PersistableTempFile::new_memfd) can't be linked anywhere, so its data is
+    /// copied to a new temporary file next to the destination, which is then persisted
+    /// instead, in the same way.
     ///
     /// (Note: Linux permits a filesystem to be mounted at multiple points,
     /// but the `link()` function does not work across different mount points,
     /// even if the same filesystem is mounted on both.)
     pub fn persist_noclobber<P: AsRef<Path>>(self, dest: P) -> Result<(), PersistError> {
+        self.persist_noclobber_file(dest.as_ref(), false).map(drop)
+    }
+
+    /// [`persist_noclobber`](PersistableTempFile::persist_noclobber), then open the new file
+    /// for reading, e.g. to `mmap` it, or check its metadata.
+    ///
+    /// The handle is to the file which was just created, even if the destination has since
+    /// been replaced: on Linux, it's reopened through `/proc/self/fd`, not by looking up `dest`
+    /// again. If it can't be reopened, or elsewhere, where only `dest` can be reopened, the
+    /// handle the data was written through (which is also writable) is returned instead.
+    pub fn persist_noclobber_open<P: AsRef<Path>>(self, dest: P) -> Result<fs::File, PersistError> {
+        let written = self.persist_noclobber_file(dest.as_ref(), false)?;
+
+        #[cfg(target_os = "linux")]
+        let reopened = {
+            use std::os::fd::AsRawFd;
+            linux::fd_path(written.as_raw_fd()).and_then(fs::File::open)
+        };
+        #[cfg(not(target_os = "linux"))]
+        let reopened = fs::File::open(dest);
+
+        Ok(reopened.unwrap_or(written))
+    }
+
+    /// `persist_noclobber`, returning the new file, open for writing.
+    /// If `copy`, copy the data across when the destination is on another filesystem, as is
+    /// always done for a memfd.
+    pub(crate) fn persist_noclobber_file(
+        self,
+        dest: &Path,
+        copy: bool,
+    ) -> Result<fs::File, PersistError> {
         match self {
             Linux(mut file) => {
                 if let Err(error) = file.flush() {
-                    return Err(PersistError::new(error, file));
+                    return Err(PersistError::new(error, file, PersistStage::Flush));
+                }
+                match linux::link_at(&file, dest) {
+                    Ok(()) => Ok(file),
+                    Err(ref error)
+                        if io::ErrorKind::CrossesDevices == error.kind()
+                            && (copy || linux::is_memfd(&file)) =>
+                    {
+                        persist_by_copy(Linux(file), dest, false)
+                    }
+                    Err(error) => Err(PersistError::new(error, file, PersistStage::Link)),
                 }
-                linux::link_at(&file, dest).map_err(|error| PersistError::new(error, file))
             }
-            Fallback(named) => named
-                .persist_noclobber(dest)
-                .map(|_| ())
-                .map_err(PersistError::from),
+            Fallback(named) => match named.persist_noclobber(dest) {
+                Ok(file) => Ok(file),
+                Err(e) if copy && io::ErrorKind::CrossesDevices == e.error.kind() => {
+                    persist_by_copy(Fallback(e.file), dest, false)
+                }
+                Err(e) => Err(PersistError::fallback(e, PersistStage::Link)),
+            },
         }
     }
 
+    /// Store this temporary file as `name`, relative to the already-open directory `dir`, like
+    /// [`persist_noclobber`](PersistableTempFile::persist_noclobber).
+    ///
+    /// The directory's path is not looked up again, so this works even if it has been renamed
+    /// since it was opened. `dir` is usually the directory the file was created in, with
+    /// [`new_in_dir_fd`](PersistableTempFile::new_in_dir_fd). The `Fallback` is persisted
+    /// through `/proc`, so this is only supported on Linux.
+    #[cfg(unix)]
+    pub fn persist_noclobber_at<P: AsRef<Path>>(
+        self,
+        dir: std::os::fd::BorrowedFd,
+        name: P,
+    ) -> Result<(), PersistError> {
+        use std::os::fd::AsRawFd;
+
+        match self {
+            Linux(mut file) => {
+                if let Err(error) = file.flush() {
+                    return Err(PersistError::new(error, file, PersistStage::Flush));
+                }
+                linux::link_at_dir(&file, dir.as_raw_fd(), name)
+                    .map_err(|error| PersistError::new(error, file, PersistStage::Link))
+            }
+            Fallback(named) => match linux::fd_path(dir.as_raw_fd()) {
+                Ok(dir) => named
+                    .persist_noclobber(dir.join(name))
+                    .map(|_| ())
+                    .map_err(|e| PersistError::fallback(e, PersistStage::Link)),
+                Err(error) => Err(PersistError {
+                    error,
+                    file: Fallback(named),
+                    stage: PersistStage::Link,
+                    dest: None,
+                }),
+            },
+        }
+    }
+
+    /// Store this temporary file as `name`, relative to the already-open directory `dir`,
+    /// replacing any existing file, like
+    /// [`persist_by_rename`](PersistableTempFile::persist_by_rename).
+    ///
+    /// Everything happens relative to `dir`, including the intermediate name, so this works
+    /// for a directory which has no usable path: e.g. a detached mount, from `open_tree()`, or
+    /// a directory from another mount namespace. Create the file in the same directory, with
+    /// [`new_in_dir_fd`](PersistableTempFile::new_in_dir_fd), and the whole flow stays within
+    /// it. `name` should be a plain name, in `dir`.
+    ///
+    /// The `Fallback` is persisted through `/proc`, so this is only supported on Linux.
+    #[cfg(unix)]
+    pub fn persist_by_rename_at<P: AsRef<Path>>(
+        self,
+        dir: std::os::fd::BorrowedFd,
+        name: P,
+    ) -> Result<(), PersistError> {
+        use std::os::fd::AsRawFd;
+
+        let dir = dir.as_raw_fd();
+        let name = name.as_ref();
+
+        let mut file = match self {
+            Linux(file) => file,
+            Fallback(named) => {
+                return match linux::fd_path(dir) {
+                    Ok(dir) => named
+                        .persist(dir.join(name))
+                        .map(|_| ())
+                        .map_err(|e| PersistError::fallback(e, PersistStage::Rename)),
+                    Err(error) => Err(PersistError {
+                        error,
+                        file: Fallback(named),
+                        stage: PersistStage::Rename,
+                        dest: None,
+                    }),
+                }
+            }
+        };
+
+        if let Err(error) = file.flush() {
+            return Err(PersistError::new(error, file, PersistStage::Flush));
+        }
+
+        if linux::link_at_dir(&file, dir, name).is_ok() {
+            return Ok(());
+        }
+
+        let temp = match random_name(Path::new(""), &mut 0, |temp| {
+            linux::link_at_dir(&file, dir, temp)
+        }) {
+            Ok(temp) => temp,
+            Err(error) => return Err(PersistError::new(error, file, PersistStage::Link)),
+        };
+
+        if let Err(error) = linux::rename_at_dir(dir, &temp, name) {
+            let _ = linux::unlink_at_dir(dir, &temp);
+            return Err(PersistError::new(error, file, PersistStage::Rename));
+        }
+
+        Ok(())
+    }
+
     /// Store this temporary file into a real name.
     ///
     /// The path must be on the same mounted filesystem. It may exist, and will be overwritten.
+    /// If it isn't on the same filesystem, this fails with [`io::ErrorKind::CrossesDevices`],
+    /// unless [`RenameOptions::copy_across_filesystems`] is set, or it's a memfd, as for
+    /// [`persist_noclobber`](PersistableTempFile::persist_noclobber), when the data is copied.
     ///
     /// This method may create a named temporary file, and, in pathological failure cases,
     /// may silently fail to remove this temporary file. Sorry.
@@ -205,53 +1012,249 @@ impl PersistableTempFile {
     /// but the `link()` function does not work across different mount points,
     /// even if the same filesystem is mounted on both.)
     pub fn persist_by_rename<P: AsRef<Path>>(self, dest: P) -> Result<(), PersistError> {
+        self.persist_by_rename_stats(
+            dest,
+            &mut RenameOptions::default(),
+            &mut PersistStats::default(),
+        )
+    }
+
+    /// [`persist_by_rename`](PersistableTempFile::persist_by_rename), with some
+    /// [`RenameOptions`], reporting how it went.
+    ///
+    /// A rising number of [`PersistReport::retries`] means intermediate names are colliding,
+    /// which suggests heavy contention in the directory, or someone deliberately creating names.
+    pub fn persist_by_rename_with<P: AsRef<Path>>(
+        self,
+        dest: P,
+        options: &mut RenameOptions,
+    ) -> Result<PersistReport, PersistError> {
+        let mut stats = PersistStats::default();
+        self.persist_by_rename_stats(dest, options, &mut stats)?;
+        Ok(PersistReport {
+            retries: stats.attempts.saturating_sub(1),
+        })
+    }
+
+    /// [`persist_by_rename`](PersistableTempFile::persist_by_rename), recording how it went.
+    pub(crate) fn persist_by_rename_stats<P: AsRef<Path>>(
+        self,
+        dest: P,
+        options: &mut RenameOptions,
+        stats: &mut PersistStats,
+    ) -> Result<(), PersistError> {
         let mut file = match self {
             Linux(file) => file,
-            Fallback(named) => return named.persist(dest).map(|_| ()).map_err(PersistError::from),
+            Fallback(named) => {
+                let mut named = Some(named);
+                let persisted = options.retry_rename(|| {
+                    let e = match named.take().expect("put back on failure").persist(&dest) {
+                        Ok(_) => return Ok(()),
+                        Err(e) => e,
+                    };
+                    named = Some(e.file);
+                    Err(e.error)
+                });
+                let error = match persisted {
+                    Ok(()) => return Ok(()),
+                    Err(error) => error,
+                };
+                let named = Fallback(named.expect("put back on failure"));
+                if options.copy_across_filesystems && io::ErrorKind::CrossesDevices == error.kind()
+                {
+                    stats.copied = true;
+                    return persist_by_copy(named, dest.as_ref(), true).map(drop);
+                }
+                return Err(PersistError {
+                    error,
+                    file: named,
+                    stage: PersistStage::Rename,
+                    dest: None,
+                });
+            }
         };
 
         if let Err(error) = file.flush() {
-            return Err(PersistError::new(error, file));
+            return Err(PersistError::new(error, file, PersistStage::Flush));
         }
 
         if linux::link_at(&file, &dest).is_ok() {
+            stats.linked_directly = true;
             return Ok(());
         };
 
-        let mut dest_tmp = dest.as_ref().to_path_buf();
-        let mut rng = ::rand::thread_rng();
-
+        let mut dir = dest.as_ref().to_path_buf();
         // pop the filename off
-        dest_tmp.pop();
-
-        for _ in 0..32768 {
-            // add a new filename
-            dest_tmp.push(format!(".{:x}.tmp", rng.next_u64()));
-
-            match linux::link_at(&file, &dest_tmp) {
-                Ok(()) => {
-                    // we succeeded in converting into a named temporary file,
-                    // now overwrite the destination
-                    return fs::rename(&dest_tmp, dest).map_err(|error| {
-                        // we couldn't overwrite the destination. Try and remove the
-                        // temporary file we created, but, if we can't, just sigh.
-                        let _ = fs::remove_file(&dest_tmp);
-
-                        PersistError::new(error, file)
-                    });
-                }
-                Err(error) => {
-                    if io::ErrorKind::AlreadyExists != error.kind() {
-                        return Err(PersistError::new(error, file));
-                    }
-                }
+        dir.pop();
+
+        if let Some(ref staging) = options.staging_dir {
+            match same_filesystem(staging, &dir) {
+                Ok(true) => dir = staging.clone(),
+                Ok(false) => (),
+                Err(error) => return Err(PersistError::new(error, file, PersistStage::TempCreate)),
+            }
+        }
+
+        let name: &mut dyn FnMut(u64) -> OsString = match options.name {
+            Some(ref mut name) => name,
+            None => &mut default_name,
+        };
+        let linked = random_name_with(&dir, &mut stats.attempts, name, |name| {
+            linux::link_at(&file, name)
+        });
+
+        let dest_tmp = match linked {
+            Ok(dest_tmp) => dest_tmp,
+            Err(ref error)
+                if io::ErrorKind::CrossesDevices == error.kind()
+                    && (options.copy_across_filesystems || linux::is_memfd(&file)) =>
+            {
+                stats.copied = true;
+                return persist_by_copy(Linux(file), dest.as_ref(), true).map(drop);
+            }
+            Err(error) => return Err(PersistError::new(error, file, PersistStage::TempCreate)),
+        };
+
+        // we succeeded in converting into a named temporary file,
+        // now overwrite the destination
+        let renamed = options.retry_rename(|| fs::rename(&dest_tmp, &dest));
+        renamed.map_err(|error| {
+            // we couldn't overwrite the destination. Try and remove the
+            // temporary file we created, but, if we can't, just sigh.
+            let stage = match fs::remove_file(&dest_tmp) {
+                Ok(()) => PersistStage::Rename,
+                Err(_) => PersistStage::Cleanup,
             };
-            dest_tmp.pop();
+
+            PersistError::new(error, file, stage)
+        })
+    }
+
+    /// [`persist_by_rename`](PersistableTempFile::persist_by_rename), trying the final rename
+    /// up to `attempts` times, with an increasing delay, starting at `backoff`, for network
+    /// filesystems where it can fail temporarily.
+    ///
+    /// This is a shorthand for [`RenameOptions::rename_attempts`], which explains which errors
+    /// are retried.
+    pub fn persist_by_rename_retry<P: AsRef<Path>>(
+        self,
+        dest: P,
+        attempts: u32,
+        backoff: Duration,
+    ) -> Result<(), PersistError> {
+        self.persist_by_rename_stats(
+            dest,
+            RenameOptions::new().rename_attempts(attempts, backoff),
+            &mut PersistStats::default(),
+        )
+    }
+
+    /// [`persist_noclobber`](PersistableTempFile::persist_noclobber), but durable: the data
+    /// is synced to disk before the file is named, and the directory is synced after, so the new
+    /// file survives a crash, once this returns.
+    ///
+    /// If syncing the data, or persisting, fails, the temporary file is handed back, as usual.
+    /// If syncing the directory fails, the file is already at the destination: the error's
+    /// [`stage`](PersistError::stage) is [`PersistStage::SyncDir`], and its `file` is a handle
+    /// to the persisted file. Directories aren't synced on Windows.
+    pub fn persist_noclobber_durable<P: AsRef<Path>>(self, dest: P) -> Result<(), PersistError> {
+        self.persist_durable(dest.as_ref(), |temp, dest| temp.persist_noclobber(dest))
+    }
+
+    /// [`persist_by_rename`](PersistableTempFile::persist_by_rename), but durable, as for
+    /// [`persist_noclobber_durable`](PersistableTempFile::persist_noclobber_durable): after a
+    /// crash, the destination is either entirely the old file, or entirely the new one.
+    pub fn persist_by_rename_durable<P: AsRef<Path>>(self, dest: P) -> Result<(), PersistError> {
+        self.persist_durable(dest.as_ref(), |temp, dest| temp.persist_by_rename(dest))
+    }
+
+    /// Sync the data, `persist` it, then sync `dest`'s directory.
+    fn persist_durable(
+        self,
+        dest: &Path,
+        persist: impl FnOnce(Self, &Path) -> Result<(), PersistError>,
+    ) -> Result<(), PersistError> {
+        if let Err(error) = self.as_ref().sync_all() {
+            return Err(PersistError {
+                error,
+                file: self,
+                stage: PersistStage::Sync,
+                dest: None,
+            });
         }
 
-        Err(PersistError::new(
-            io::Error::new(io::ErrorKind::Other, "couldn't create temporary file"),
-            file,
-        ))
+        // the same file as `self`, for reporting a failure after `self` is gone
+        let persisted = match self.as_ref().try_clone() {
+            Ok(persisted) => persisted,
+            Err(error) => {
+                return Err(PersistError {
+                    error,
+                    file: self,
+                    stage: PersistStage::Sync,
+                    dest: None,
+                })
+            }
+        };
+
+        persist(self, dest)?;
+        sync_dir_of(dest)
+            .map_err(|error| PersistError::new(error, persisted, PersistStage::SyncDir))
+    }
+
+    /// [`persist_by_rename`](PersistableTempFile::persist_by_rename), taking ownership of the
+    /// destination, and handing it back on success, so it doesn't need cloning beforehand.
+    ///
+    /// On failure, the destination is in the error: see [`PersistError::take_dest`].
+    pub fn persist_by_rename_path(self, dest: PathBuf) -> Result<PathBuf, PersistError> {
+        match self.persist_by_rename(&dest) {
+            Ok(()) => Ok(dest),
+            Err(mut persist_error) => {
+                persist_error.dest = Some(dest);
+                Err(persist_error)
+            }
+        }
+    }
+
+    /// Replace the contents of an existing file with the contents of this temporary file,
+    /// keeping the destination's inode.
+    ///
+    /// **This is not atomic, and not crash safe.** The destination is truncated, then the new
+    /// content is copied in; anyone reading the destination in the meantime (or after a crash)
+    /// may see it empty, or partially written.
+    ///
+    /// This is for destinations where the inode must not change, e.g. because other processes
+    /// hold it open, or it has other hard links. If you don't need this, use
+    /// [`persist_by_rename`](PersistableTempFile::persist_by_rename), which is atomic.
+    ///
+    /// The destination is created if it does not exist. Its ownership and permissions are not
+    /// changed. The copy is a reflink where the filesystem supports it, so is cheap on e.g.
+    /// btrfs and xfs.
+    ///
+    /// On error, the position of the temporary file is unspecified.
+    pub fn persist_in_place<P: AsRef<Path>>(mut self, dest: P) -> Result<(), PersistError> {
+        if let Err(error) = self.flush() {
+            return Err(PersistError {
+                error,
+                file: self,
+                stage: PersistStage::Flush,
+                dest: None,
+            });
+        }
+
+        match fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dest)
+            .and_then(|dest| copy::reflink_or_copy(self.as_ref(), &dest))
+        {
+            Ok(()) => Ok(()),
+            Err(error) => Err(PersistError {
+                error,
+                file: self,
+                stage: PersistStage::Copy,
+                dest: None,
+            }),
+        }
     }
 }
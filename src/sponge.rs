@@ -1,10 +1,30 @@
 use std::env;
+use std::error;
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
 use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
+use super::copy;
+use super::linux;
+use super::persistable::name_in;
+use super::persistable::random_name;
+use super::persistable::sync_dir_of;
+use super::persistable::PersistStats;
+use super::Barrier;
+use super::PersistError;
 use super::PersistableTempFile;
+use super::RenameOptions;
 
 /// A safer abstraction for atomic overwrites of files.
 ///
@@ -33,113 +53,2475 @@ use super::PersistableTempFile;
 /// ```
 pub struct Sponge {
     dest: PathBuf,
-    temp: io::BufWriter<PersistableTempFile>,
+    metadata: MetadataOptions,
+    temp: io::BufWriter<Scratch>,
+    read_pos: u64,
+    written: u64,
+    max_size: Option<u64>,
+    metadata_locked: bool,
+    strict_device: bool,
+    follow_symlinks: bool,
+    sharing_retries: u32,
+    /// The destination when the `Sponge` was created, or `None` if it didn't exist.
+    original: Option<Snapshot>,
+    require_unchanged: bool,
+    recreate_parent: bool,
+    /// The mode for any directories the `Sponge` creates, from the `SpongeBuilder`.
+    dir_mode: Option<u32>,
+    /// Where the temporary file was deliberately created, away from the destination, so may be
+    /// on another filesystem.
+    scratch_dir: Option<PathBuf>,
+    /// Held until the `Sponge` is committed, or dropped.
+    _lock: Option<fs::File>,
+}
+
+/// A borrowed `Sponge`, which can only be written to (and read back, and seeked), not committed.
+///
+/// Returned by [`Sponge::writer`], to pass to code which should produce the content, but has no
+/// business deciding whether the destination is replaced.
+pub struct SpongeWriter<'s> {
+    sponge: &'s mut Sponge,
+}
+
+/// Writes to a [`Sponge`], and copies everything written to another writer: see
+/// [`Sponge::tee`].
+pub struct SpongeTee<'s, W> {
+    sponge: &'s mut Sponge,
+    other: W,
+    /// The first failure writing to `other`, after which it's not written to again.
+    error: Option<io::Error>,
+}
+
+/// Reads the destination of a [`Sponge`], releasing its storage as it goes: see
+/// [`Sponge::consume_destination`], and its warnings.
+#[derive(Debug)]
+pub struct ConsumingReader {
+    file: fs::File,
+    block_size: u64,
+    /// Where the next read will come from.
+    pos: u64,
+    /// Everything before this has been released.
+    released: u64,
+    /// Whether the filesystem can release storage at all.
+    punching: bool,
+    /// A failure to release storage, to report on the next read, as the read which caused it
+    /// had already succeeded.
+    error: Option<io::Error>,
+}
+
+impl io::Read for ConsumingReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        let read = self.file.read(buf)?;
+        self.pos += read as u64;
+
+        let consumed = self.pos - self.pos % self.block_size;
+        if self.punching && consumed > self.released {
+            match linux::punch_hole(&self.file, self.released, consumed - self.released) {
+                Ok(()) => self.released = consumed,
+                Err(e) => {
+                    // stop trying; the data just read is still good
+                    self.punching = false;
+                    if io::ErrorKind::Unsupported != e.kind() {
+                        self.error = Some(e);
+                    }
+                }
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+/// A [`Sponge::commit`] waiting to happen: see [`Sponge::into_commit_job`].
+///
+/// This owns everything the commit needs (the temporary file, the destination, and the metadata
+/// options), so can be sent to another thread.
+#[derive(Debug)]
+pub struct CommitJob {
+    sponge: Sponge,
+}
+
+impl CommitJob {
+    /// Commit, exactly as [`Sponge::commit`] would.
+    pub fn run(self) -> Result<(), io::Error> {
+        self.sponge
+            .commit_inner(Durability::None, |_| Ok(()))
+            .map(|_| ())
+    }
+
+    /// The destination which will be replaced.
+    pub fn dest(&self) -> &Path {
+        &self.sponge.dest
+    }
+}
+
+/// Where a [`Sponge`]'s writes go: see [`Sponge::backend`], and [`CommitStats::backend`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Backend {
+    /// An anonymous `O_TMPFILE` file, linked into place at `commit()` time: the fast path.
+    Linux,
+    /// A named temporary file, from the `tempfile` crate, renamed into place.
+    Fallback,
+    /// The destination itself, which isn't a regular file; see
+    /// [`SpongeBuilder::allow_non_regular`].
+    Direct,
+}
+
+/// What happened during a [`Sponge::commit_with_stats`].
+///
+/// These are for monitoring, e.g. noticing that the fast path is unexpectedly not being taken
+/// on some filesystem; the details are subject to change.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CommitStats {
+    /// Where the writes went, as for [`Sponge::backend`].
+    pub backend: Backend,
+    /// The temporary file was linked straight to the destination, which didn't exist, with no
+    /// intermediate name. Only possible on the `Linux` fast path.
+    pub linked_directly: bool,
+    /// The number of intermediate names tried, before one was free, on the `Linux` fast path.
+    /// Usually `0` (linked directly), or `1`.
+    pub rename_attempts: u32,
+    /// The temporary file couldn't be linked, so its data was copied to a new file.
+    pub copy_fallback: bool,
+    /// The existing destination was replaced with `ReplaceFileW`, keeping its ACLs and
+    /// alternate data streams, instead of being renamed over. Only on Windows.
+    pub replaced: bool,
+    /// The total bytes written to the `Sponge`, as for [`Sponge::bytes_written`].
+    pub bytes_written: u64,
+    /// How long the commit took, including writing out the buffer.
+    pub elapsed: Duration,
+    /// The destination's permissions couldn't be applied to the new file, which kept the
+    /// temporary file's, as allowed by [`SpongeBuilder::best_effort_permissions`].
+    pub permissions_skipped: bool,
+}
+
+/// The file written by a [`Sponge::commit_audited`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CommitRecord {
+    /// The path which was replaced: the destination, or, with
+    /// [`SpongeBuilder::follow_symlinks`], the file it links to.
+    pub path: PathBuf,
+    /// The length of the new file, in bytes.
+    pub len: u64,
+    /// The new file's permissions.
+    pub permissions: fs::Permissions,
+    /// The new file's mode, including the file type bits.
+    #[cfg(unix)]
+    pub mode: u32,
+    /// The new file's owner.
+    #[cfg(unix)]
+    pub uid: u32,
+    /// The new file's group.
+    #[cfg(unix)]
+    pub gid: u32,
+    /// The `Linux` fast path was used: the new file had no name at all while it was being
+    /// written, so was only ever visible complete, even if it was linked to an intermediate
+    /// name before being renamed into place.
+    pub fast_path: bool,
+}
+
+impl CommitRecord {
+    fn new(path: PathBuf, metadata: &fs::Metadata, fast_path: bool) -> CommitRecord {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        CommitRecord {
+            path,
+            len: metadata.len(),
+            permissions: metadata.permissions(),
+            #[cfg(unix)]
+            mode: metadata.mode(),
+            #[cfg(unix)]
+            uid: metadata.uid(),
+            #[cfg(unix)]
+            gid: metadata.gid(),
+            fast_path,
+        }
+    }
+}
+
+/// The destination changed between creating a [`Sponge`] and
+/// [`Sponge::commit_if_unchanged`], so it wasn't replaced.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct DestinationChanged {
+    /// The path which would have been replaced.
+    pub dest: PathBuf,
+}
+
+impl fmt::Display for DestinationChanged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} was changed by someone else, so was not replaced",
+            self.dest
+        )
+    }
+}
+
+impl error::Error for DestinationChanged {}
+
+/// Enough of a file's metadata to notice that it has been changed.
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    len: u64,
+    modified: Option<SystemTime>,
+    #[cfg(unix)]
+    inode: (u64, u64),
+}
+
+impl Snapshot {
+    fn of(metadata: &fs::Metadata) -> Snapshot {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        Snapshot {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            #[cfg(unix)]
+            inode: (metadata.dev(), metadata.ino()),
+        }
+    }
+}
+
+/// What `commit_inner` found out along the way.
+struct Committed<M> {
+    stats: CommitStats,
+    /// The path which was replaced.
+    dest: PathBuf,
+    /// What `commit_inner`'s `inspect` made of the new file, just before it was committed.
+    inspected: M,
+    /// The new file was written on the `Linux` fast path, and linked into place.
+    fast_path: bool,
+}
+
+/// Where the writes to a `Sponge` actually go.
+enum Scratch {
+    /// The usual case: a temporary file, eventually renamed over the destination.
+    Temp(PersistableTempFile),
+    /// The destination itself, which isn't a regular file, so can't be replaced.
+    Direct(fs::File),
+}
+
+impl fmt::Debug for Scratch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Scratch::Temp(ref temp) => temp.fmt(f),
+            Scratch::Direct(_) => write!(f, "Direct"),
+        }
+    }
+}
+
+/// How the temporary file's ownership and permissions are decided, at `commit()` time.
+#[derive(Default)]
+struct MetadataOptions {
+    /// Copy from here, instead of from the destination.
+    template: Option<PathBuf>,
+    /// If there's nothing to copy from, mask `0o666` with the `umask`, like a normal create.
+    apply_umask: bool,
+    /// Carry on if the permissions can't be applied, instead of failing.
+    best_effort_permissions: bool,
+    /// If the owner can't be copied, copy just the group, or neither, instead of failing.
+    best_effort_group: bool,
+    /// Copy the access and modification times, too.
+    preserve_times: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Durability {
+    None,
+    File,
+    FileAndParent,
+}
+
+/// Options for creating a [`Sponge`].
+///
+/// ```rust
+/// # use std::io::Write;
+/// let mut out = tempfile_fast::Sponge::builder()
+///     .allow_non_regular(true)
+///     .new_for("example.txt")
+///     .unwrap();
+/// out.write_all(b"hello").unwrap();
+/// out.commit().unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SpongeBuilder {
+    allow_non_regular: bool,
+    dir_mode: Option<u32>,
+    max_size: Option<u64>,
+    strict_device: bool,
+    apply_umask: bool,
+    follow_symlinks: bool,
+    sharing_retries: u32,
+    recreate_parent: bool,
+    lock: Option<PathBuf>,
+    best_effort_permissions: bool,
+    best_effort_group: bool,
+    preserve_times: bool,
+}
+
+impl SpongeBuilder {
+    /// Create a builder with the default options, i.e. those used by [`Sponge::new_for`].
+    pub fn new() -> SpongeBuilder {
+        SpongeBuilder::default()
+    }
+
+    /// Write directly to destinations which aren't regular files, i.e. character devices
+    /// (like `/dev/stdout` or `/dev/null`) and FIFOs (pipes).
+    ///
+    /// Atomic replacement makes no sense for these destinations. With this enabled, the
+    /// destination is opened immediately, writes go (through the buffer) straight to it, and
+    /// `commit()` is only a `flush()`. None of the atomicity guarantees of `Sponge` apply:
+    /// anything written is immediately (or eventually) visible, even if the `Sponge` is never
+    /// committed.
+    ///
+    /// This is useful for tools which accept `-` to mean "stdout", and would like to treat it
+    /// like any other output file.
+    ///
+    /// Default: `false`, the destination is replaced, as if it was a regular file.
+    ///
+    /// Opening a FIFO for writing blocks until there is a reader.
+    pub fn allow_non_regular(&mut self, allow: bool) -> &mut SpongeBuilder {
+        self.allow_non_regular = allow;
+        self
+    }
+
+    /// Create any missing parent directories of the destination with `mode` (e.g. `0o700`, for
+    /// a directory which will hold secrets), instead of the default of `0o777`.
+    ///
+    /// Only directories the `Sponge` creates itself are affected: when it's created, and, with
+    /// [`SpongeBuilder::recreate_parent`], at `commit()` time. Directories which already exist
+    /// keep their permissions. As with any `mkdir`, the process' `umask` is still applied.
+    ///
+    /// This only does anything on `unix`; elsewhere, it's ignored.
+    pub fn dir_mode(&mut self, mode: u32) -> &mut SpongeBuilder {
+        self.dir_mode = Some(mode);
+        self
+    }
+
+    /// Refuse writes which would take the total written to the `Sponge` over `limit` bytes.
+    ///
+    /// The `write` which would exceed the limit fails with [`io::ErrorKind::FileTooLarge`],
+    /// and none of it is written. Everything written before then is still present.
+    ///
+    /// This is a guard against a runaway producer filling the disk. Only bytes written through
+    /// the `Sponge` count towards the limit.
+    ///
+    /// Default: no limit.
+    pub fn max_size(&mut self, limit: u64) -> &mut SpongeBuilder {
+        self.max_size = Some(limit);
+        self
+    }
+
+    /// Check, at `commit()` time, that the temporary file is on the same device as the
+    /// destination's directory, before trying to move it there.
+    ///
+    /// The temporary file is created in the destination's directory, so this should always be
+    /// true, but bind mounts, or a directory being replaced (or mounted over) after the `Sponge`
+    /// was created, can break it. Without the check, the failure is an unhelpful `EXDEV` from
+    /// deep inside the persist. With it, the error is [`io::ErrorKind::CrossesDevices`],
+    /// explaining the problem, and nothing is attempted.
+    ///
+    /// It needs `st_dev`, so is only done on `unix`.
+    ///
+    /// Default: `false`.
+    pub fn strict_device(&mut self, strict: bool) -> &mut SpongeBuilder {
+        self.strict_device = strict;
+        self
+    }
+
+    /// When the destination doesn't exist at `commit()` time, give the new file the permissions
+    /// a normal file creation would: `0o666`, masked by the process' current `umask`.
+    ///
+    /// Without this, a new destination keeps the temporary file's permissions. On Linux, with
+    /// `O_TMPFILE`, and in the fallback, the temporary file is created with mode `0o600` (see
+    /// [`Builder::temp_mode`](struct.Builder.html#method.temp_mode)), so, with the usual `umask`
+    /// of `0o022`, a new file is `0o600`, where `File::create` would have made it `0o644`.
+    /// This is the safe choice for files which may be private, so remains the default.
+    ///
+    /// If the destination exists (or a template was given to [`Sponge::new_for_like`]), its
+    /// permissions are copied as usual, and this has no effect. The `umask` is read at
+    /// `commit()` time (or by [`Sponge::lock_metadata`]), from `/proc/self/status`. Where that
+    /// isn't available (older Linux, most other `unix`es, and Windows), this has no effect, as
+    /// the only other way to read the `umask` briefly changes it for the whole process.
+    ///
+    /// Default: `false`.
+    pub fn apply_umask(&mut self, apply: bool) -> &mut SpongeBuilder {
+        self.apply_umask = apply;
+        self
+    }
+
+    /// If the destination is a symlink at `commit()` time, replace the file it points to, instead
+    /// of replacing the symlink with a regular file.
+    ///
+    /// Chains of symlinks are followed to the final target, which need not exist. Relative links
+    /// are resolved against the directory containing the link. A loop (or a chain of more than
+    /// 40 links) is an [`io::ErrorKind::InvalidInput`] error, and nothing is replaced.
+    ///
+    /// The replacement is still atomic, but the target must be on the same filesystem as the
+    /// destination's directory, where the temporary file was created. The target's parent
+    /// directory is the one synced by [`Sponge::commit_durable`].
+    ///
+    /// Default: `false`, the symlink itself is replaced.
+    pub fn follow_symlinks(&mut self, follow: bool) -> &mut SpongeBuilder {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// On Windows, retry the final replace up to `retries` times, with an increasing delay
+    /// (from 10ms, doubling each time), if it fails because another process has the destination
+    /// open.
+    ///
+    /// Indexers, antivirus, and file watchers often briefly open files, which makes the replace
+    /// fail with a sharing violation (or access denied). If it's still failing after the
+    /// retries, the error is [`io::ErrorKind::ResourceBusy`], wrapping the last error from the
+    /// system. With no retries, the system's error is returned as it is.
+    ///
+    /// Other errors are not retried. Other platforms don't have this problem, so this does
+    /// nothing there.
+    ///
+    /// Default: `0`, no retries.
+    pub fn sharing_retries(&mut self, retries: u32) -> &mut SpongeBuilder {
+        self.sharing_retries = retries;
+        self
+    }
+
+    /// If the destination's directory has been removed since the `Sponge` was created, create
+    /// it (and any missing parents) again at `commit()` time, instead of failing.
+    ///
+    /// This only helps on the `Linux` fast path, where the temporary file has no name, so
+    /// survives its directory being removed. The `Fallback`'s named temporary file is removed
+    /// along with the directory, so the commit still fails.
+    ///
+    /// Without this, the commit fails with [`io::ErrorKind::NotFound`], explaining that the
+    /// directory no longer exists.
+    ///
+    /// Default: `false`.
+    pub fn recreate_parent(&mut self, recreate: bool) -> &mut SpongeBuilder {
+        self.recreate_parent = recreate;
+        self
+    }
+
+    /// Take an exclusive advisory lock on `lock_path` (creating it, if necessary) when the
+    /// `Sponge` is created, waiting for anyone else holding it, and keep it until the `Sponge`
+    /// has been committed, or dropped.
+    ///
+    /// If every writer of a destination takes the same lock, they take turns, instead of the
+    /// last rename silently winning. Create the `Sponge` before reading anything the new content
+    /// is based on. This only coordinates cooperating processes: the lock doesn't stop anyone
+    /// else from replacing the destination.
+    ///
+    /// Use a separate lock file, e.g. `foo.conf.lock`, which is never replaced: locking the
+    /// destination itself doesn't work, as the commit replaces it with a new, unlocked, file.
+    /// The lock file is left behind afterwards, as removing it would let another writer lock a
+    /// different file of the same name.
+    ///
+    /// This uses [`fs::File::lock`]: `flock` on `unix`, and `LockFileEx` on Windows.
+    ///
+    /// Default: no lock.
+    pub fn with_lock<P: AsRef<Path>>(&mut self, lock_path: P) -> &mut SpongeBuilder {
+        self.lock = Some(lock_path.as_ref().to_path_buf());
+        self
+    }
+
+    /// If the destination's permissions can't be applied to the new file, e.g. on a filesystem
+    /// which rejects mode changes, commit anyway, with the temporary file's permissions
+    /// (usually `0o600`), instead of failing.
+    ///
+    /// Only a refusal (`EPERM`, or `EOPNOTSUPP`: [`io::ErrorKind::PermissionDenied`] or
+    /// [`io::ErrorKind::Unsupported`]) is skipped; any other error still fails the commit.
+    ///
+    /// The skip is silent: it's only reported, in [`CommitStats::permissions_skipped`], by
+    /// [`Sponge::commit_with_stats`]. Ownership is still copied strictly.
+    ///
+    /// Default: `false`.
+    pub fn best_effort_permissions(&mut self, best_effort: bool) -> &mut SpongeBuilder {
+        self.best_effort_permissions = best_effort;
+        self
+    }
+
+    /// If the destination's owner can't be copied, copy just its group, instead of failing the
+    /// commit, e.g. for an unprivileged process, in a shared directory, updating a file owned
+    /// by someone else.
+    ///
+    /// An unprivileged process can only give a file to a group it's a member of, and can't
+    /// change the owner at all. With this set, if setting both is refused (`EPERM`), the group
+    /// alone is tried. If that's refused too, the new file keeps the process' user and
+    /// (usually) its primary group, and the commit carries on. Other errors still fail.
+    ///
+    /// The permissions are then copied as usual, so a file which was only readable by its
+    /// group may end up readable only by this process' user. This only does anything on `unix`.
+    ///
+    /// Default: `false`.
+    pub fn best_effort_group(&mut self, best_effort: bool) -> &mut SpongeBuilder {
+        self.best_effort_group = best_effort;
+        self
+    }
+
+    /// Give the new file the destination's access and modification times, instead of the time
+    /// of the commit, e.g. for a tool which rewrites files without changing what they mean.
+    ///
+    /// The times are set on the temporary file (with `futimens`), after everything has been
+    /// written, and before it's linked or renamed into place, so the new file never appears
+    /// under the destination's name with any other times. They're read from the destination
+    /// (or the template given to [`Sponge::new_for_like`]) at `commit()` time, even after
+    /// [`Sponge::lock_metadata`]. The change time (`ctime`) can't be set, so is always the
+    /// time of the commit. If the data has to be copied to another filesystem (see
+    /// [`CommitStats::copy_fallback`]), the copy gets the time of the commit.
+    ///
+    /// A destination which doesn't exist yet has no times to copy, so the new file keeps the
+    /// time of the commit. Windows always keeps the destination's times.
+    ///
+    /// Default: `false`.
+    pub fn preserve_times(&mut self, preserve: bool) -> &mut SpongeBuilder {
+        self.preserve_times = preserve;
+        self
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the named file.
+    ///
+    /// See [`Sponge::new_for`] for details.
+    pub fn new_for<P: AsRef<Path>>(&self, path: P) -> Result<Sponge, io::Error> {
+        self.create(absolute(path.as_ref())?)
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the named file, without resolving it
+    /// against the current directory.
+    ///
+    /// See [`Sponge::new_for_relative`] for details.
+    pub fn new_for_relative<P: AsRef<Path>>(&self, path: P) -> Result<Sponge, io::Error> {
+        self.create(path.as_ref().to_path_buf())
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the named file, with its temporary
+    /// file in `scratch_dir`.
+    ///
+    /// See [`Sponge::new_for_with_scratch`] for details.
+    pub fn new_for_with_scratch<P: AsRef<Path>, S: AsRef<Path>>(
+        &self,
+        path: P,
+        scratch_dir: S,
+    ) -> Result<Sponge, io::Error> {
+        self.create_in(absolute(path.as_ref())?, Some(scratch_dir.as_ref()))
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the file called `filename`, in `dir`,
+    /// refusing names which could escape `dir`.
+    ///
+    /// See [`Sponge::new_in_dir_named`] for details.
+    pub fn new_in_dir_named(&self, dir: &Path, filename: &OsStr) -> Result<Sponge, io::Error> {
+        let mut components = Path::new(filename).components();
+        let plain = match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => name == filename,
+            _ => false,
+        };
+
+        if !plain || filename.as_encoded_bytes().contains(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{filename:?} is not a plain file name"),
+            ));
+        }
+
+        self.new_for(dir.join(filename))
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the named file, taking the ownership
+    /// and permissions from `template`.
+    ///
+    /// See [`Sponge::new_for_like`] for details.
+    pub fn new_for_like<P: AsRef<Path>, T: AsRef<Path>>(
+        &self,
+        path: P,
+        template: T,
+    ) -> Result<Sponge, io::Error> {
+        let template = absolute(template.as_ref())?;
+        template.metadata()?;
+
+        let mut sponge = self.new_for(path)?;
+        sponge.metadata.template = Some(template);
+        Ok(sponge)
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the named file, with the given
+    /// ownership and permissions.
+    ///
+    /// See [`Sponge::new_for_as`] for details.
+    #[cfg(unix)]
+    pub fn new_for_as<P: AsRef<Path>>(
+        &self,
+        path: P,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> Result<Sponge, io::Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut sponge = self.new_for(path)?;
+        {
+            let file = sponge.temp.get_ref().file();
+            unix_chown::chown_to(file, uid, gid)?;
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+        sponge.metadata_locked = true;
+        Ok(sponge)
+    }
+
+    fn create(&self, path: PathBuf) -> Result<Sponge, io::Error> {
+        self.create_in(path, None)
+    }
+
+    /// Create the `Sponge`, with its temporary file in `scratch_dir`, if given, otherwise next
+    /// to the destination.
+    fn create_in(&self, path: PathBuf, scratch_dir: Option<&Path>) -> Result<Sponge, io::Error> {
+        // before looking at the destination, so it's seen as the previous lock holder left it
+        let lock = self.lock.as_deref().map(take_lock).transpose()?;
+
+        let existing = path.metadata().ok();
+
+        if ends_with_separator(&path) || existing.as_ref().is_some_and(fs::Metadata::is_dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("destination {path:?} is a directory"),
+            ));
+        }
+
+        if self.allow_non_regular && existing.as_ref().is_some_and(is_non_regular) {
+            let file = fs::OpenOptions::new().write(true).open(&path)?;
+            return Ok(self.build(path, Scratch::Direct(file), None, lock));
+        }
+
+        let parent = parent_dir(&path).ok_or_else(|| no_parent(&path))?;
+
+        #[cfg(windows)]
+        check_volume(&path)?;
+
+        // an existing destination's directory must exist; don't pay for `mkdir` and `stat`
+        if existing.is_none() {
+            create_dirs(parent, self.dir_mode)?;
+        }
+
+        let temp = PersistableTempFile::new_in(scratch_dir.unwrap_or(parent))?;
+        let original = existing.as_ref().map(Snapshot::of);
+        let mut sponge = self.build(path, Scratch::Temp(temp), original, lock);
+        sponge.scratch_dir = scratch_dir.map(Path::to_path_buf);
+        Ok(sponge)
+    }
+
+    fn build(
+        &self,
+        dest: PathBuf,
+        scratch: Scratch,
+        original: Option<Snapshot>,
+        lock: Option<fs::File>,
+    ) -> Sponge {
+        Sponge {
+            dest,
+            metadata: MetadataOptions {
+                apply_umask: self.apply_umask,
+                best_effort_permissions: self.best_effort_permissions,
+                best_effort_group: self.best_effort_group,
+                preserve_times: self.preserve_times,
+                ..MetadataOptions::default()
+            },
+            temp: io::BufWriter::new(scratch),
+            read_pos: 0,
+            written: 0,
+            max_size: self.max_size,
+            metadata_locked: false,
+            strict_device: self.strict_device,
+            follow_symlinks: self.follow_symlinks,
+            sharing_retries: self.sharing_retries,
+            original,
+            require_unchanged: false,
+            recreate_parent: self.recreate_parent,
+            dir_mode: self.dir_mode,
+            scratch_dir: None,
+            _lock: lock,
+        }
+    }
 }
 
 impl Sponge {
     /// Create a `Sponge` which will eventually overwrite the named file.
     /// The file does not have to exist.
     ///
-    /// This will be resolved to an absolute path relative to the current directory immediately.
+    /// A relative path is resolved to an absolute path, against the current directory, once,
+    /// immediately. An absolute path is used as it is, without looking up the current directory.
     ///
     /// The path is *not* run through [`fs::canonicalize`], so other oddities will resolve
     /// at `commit()` time. Notably, a `symlink` (or `hardlink`, or `reflink`) will be converted
-    /// into a regular file, using the target's [`fs::metadata`].
+    /// into a regular file, using the target's [`fs::metadata`], unless
+    /// [`SpongeBuilder::follow_symlinks`] is set.
+    ///
+    /// If the path is an existing directory, or ends with a path separator (so can only be a
+    /// directory), this fails immediately with [`io::ErrorKind::IsADirectory`]. A bare file name
+    /// is in the current directory. Only a path with no parent at all (such as a Windows drive
+    /// prefix) fails with [`io::ErrorKind::InvalidInput`]; `/` is a directory.
     ///
     /// Intermediate directories will be created using the platform defaults (e.g. permissions),
-    /// if this is not what you want, create them in advance.
+    /// if this is not what you want, create them in advance, or see
+    /// [`SpongeBuilder::dir_mode`].
+    ///
+    /// The temporary file is created now, so a destination which can't be written (e.g. on a
+    /// read-only filesystem) fails here, before any work is done. On Windows, a drive or share
+    /// which doesn't exist is reported as such.
+    ///
+    /// Paths are never converted to strings, so, on `unix`, they need not be valid UTF-8; any
+    /// bytes the platform allows in a file name are fine, for both the destination and its
+    /// parent directories.
     pub fn new_for<P: AsRef<Path>>(path: P) -> Result<Sponge, io::Error> {
-        let path = path.as_ref();
+        SpongeBuilder::new().new_for(path)
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the named file, like [`Sponge::new_for`],
+    /// but keep a relative `path` as it is, instead of resolving it against the current
+    /// directory now.
+    ///
+    /// Every operation uses the path as given, so a relative path is resolved against whatever
+    /// the current directory is *at the time*: the temporary file is created relative to the
+    /// current directory now, and the destination (and its metadata) is found relative to the
+    /// current directory at `commit()` time. If the process changes directory in between, the
+    /// commit will try to move the temporary file into a different directory, which may be on
+    /// a different filesystem, and fail.
+    ///
+    /// Use `new_for` (the usual choice) to pin the destination when the `Sponge` is created,
+    /// and this for programs which deliberately follow the current directory, e.g. because it
+    /// is a name which must be resolved in the same way as the caller's other relative paths.
+    pub fn new_for_relative<P: AsRef<Path>>(path: P) -> Result<Sponge, io::Error> {
+        SpongeBuilder::new().new_for_relative(path)
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the file called `filename`, in `dir`,
+    /// like [`Sponge::new_for`], for names which come from elsewhere, and must not be allowed to
+    /// escape `dir`.
+    ///
+    /// `filename` must be a single, plain, file name: it fails with
+    /// [`io::ErrorKind::InvalidInput`] if it's empty, `.` or `..`, or contains a path separator
+    /// (or a drive prefix, on Windows), or a NUL.
+    pub fn new_in_dir_named(dir: &Path, filename: &OsStr) -> Result<Sponge, io::Error> {
+        SpongeBuilder::new().new_in_dir_named(dir, filename)
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the named file, like [`Sponge::new_for`],
+    /// but with the temporary file in `scratch_dir`, e.g. a faster disk, instead of next to the
+    /// destination.
+    ///
+    /// If `scratch_dir` turns out to be on the same filesystem as the destination, `commit()`
+    /// is as cheap as usual. If not, the data is copied into a new temporary file next to the
+    /// destination, which then replaces it, so the replacement is still atomic, just slower.
+    /// [`Sponge::commit_with_stats`] reports which happened, in
+    /// [`CommitStats::copy_fallback`]. [`SpongeBuilder::strict_device`] is ignored.
+    pub fn new_for_with_scratch<P: AsRef<Path>, S: AsRef<Path>>(
+        path: P,
+        scratch_dir: S,
+    ) -> Result<Sponge, io::Error> {
+        SpongeBuilder::new().new_for_with_scratch(path, scratch_dir)
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the named file, like [`Sponge::new_for`],
+    /// but take the ownership and permissions from `template`, instead of from the destination.
+    ///
+    /// This is useful for creating a new file which should look like an existing one, e.g. a
+    /// new log file with the same mode and group as its siblings. The template is used even if
+    /// the destination already exists.
+    ///
+    /// The template must exist now; it is resolved to an absolute path, like the destination,
+    /// and its metadata is read at `commit()` time (or by [`Sponge::lock_metadata`]).
+    pub fn new_for_like<P: AsRef<Path>, T: AsRef<Path>>(
+        path: P,
+        template: T,
+    ) -> Result<Sponge, io::Error> {
+        SpongeBuilder::new().new_for_like(path, template)
+    }
+
+    /// Create a `Sponge` which will eventually overwrite the named file, like [`Sponge::new_for`],
+    /// with the given ownership and permissions, instead of copying the destination's.
+    ///
+    /// The ownership and permissions are applied to the temporary file immediately, so, if the
+    /// process isn't allowed to `chown` to `uid` and `gid` (which usually needs privilege), this
+    /// fails now, not after the expensive writes. `commit()` does not copy any metadata, as if
+    /// [`Sponge::lock_metadata`] had been called.
+    #[cfg(unix)]
+    pub fn new_for_as<P: AsRef<Path>>(
+        path: P,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> Result<Sponge, io::Error> {
+        SpongeBuilder::new().new_for_as(path, uid, gid, mode)
+    }
+
+    /// Replace the file at `path` with `transform` applied to its current content (empty if it
+    /// doesn't exist yet), and [`Sponge::commit`].
+    ///
+    /// ```rust
+    /// tempfile_fast::Sponge::edit("example.txt", |old| {
+    ///     let mut new = old.to_vec();
+    ///     new.extend_from_slice(b"one more line\n");
+    ///     new
+    /// })
+    /// .unwrap();
+    /// ```
+    ///
+    /// The destination is opened once, and read in full, before `transform` is called, so it
+    /// sees one consistent version of the file, even if it's replaced in the meantime. That
+    /// later change is overwritten, though; this isn't a lock.
+    ///
+    /// This holds the whole file in memory, twice; it's for small files, like configuration.
+    /// For anything bigger, read the destination and write to a `Sponge` as a stream.
+    pub fn edit<P, F>(path: P, transform: F) -> Result<(), io::Error>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&[u8]) -> Vec<u8>,
+    {
+        let mut sponge = Sponge::new_for(path)?;
+
+        let mut old = Vec::new();
+        match fs::File::open(&sponge.dest) {
+            Ok(mut file) => {
+                io::Read::read_to_end(&mut file, &mut old)?;
+            }
+            Err(ref e) if io::ErrorKind::NotFound == e.kind() => (),
+            Err(e) => return Err(e),
+        }
+
+        sponge.write_all(&transform(&old))?;
+        sponge.commit()
+    }
+
+    /// Create a [`SpongeBuilder`], to customise how the `Sponge` is created.
+    pub fn builder() -> SpongeBuilder {
+        SpongeBuilder::new()
+    }
+
+    /// Fill `len` bytes of the temporary file, at `offset`, with the same range of the current
+    /// destination, e.g. to keep the unchanged parts of a large file, and only write the
+    /// changes (after a `seek`).
+    ///
+    /// On filesystems which support it (btrfs, xfs), this shares the blocks with the destination
+    /// (`FICLONERANGE`), so is nearly free, and uses no extra space. This needs `offset` and
+    /// `len` to be multiples of the filesystem's block size, except that the range may end at the
+    /// end of the destination. Otherwise, or elsewhere, the data is copied, stopping early if the
+    /// destination is shorter.
+    ///
+    /// Any buffered writes are written out first. The write position is not changed, and the
+    /// bytes are not counted by [`Sponge::bytes_written`] (or [`SpongeBuilder::max_size`]).
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// this fails with [`io::ErrorKind::Unsupported`].
+    pub fn clone_range_from_existing(&mut self, offset: u64, len: u64) -> Result<(), io::Error> {
+        self.temp.flush()?;
+
+        let existing = fs::File::open(self.target()?)?;
+        let scratch = self.temp.get_mut();
+        let temp = match scratch {
+            Scratch::Temp(temp) => temp,
+            Scratch::Direct(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "can't clone into a destination which is written directly",
+                ))
+            }
+        };
+
+        let write_pos = temp.stream_position()?;
+        let cloned = copy::clone_or_copy_range(&existing, temp.as_ref(), offset, len);
+        temp.seek(SeekFrom::Start(write_pos))?;
+        cloned
+    }
+
+    /// **Destructively** read the current destination, releasing its storage as it is read,
+    /// for producing the new content from the old on a filesystem without room for both.
+    ///
+    /// **This changes the destination before `commit()`, and is not atomic, or crash safe.**
+    /// Everything the reader has read past is replaced with a hole, which reads as zeros, in
+    /// the destination itself. If the `Sponge` isn't committed (an error, a panic, a crash, or
+    /// running out of space anyway), the destination is left with its start zeroed. Other
+    /// names for the same file (hard links) see the same damage. Only use this when losing the
+    /// old content is acceptable, or it can be recreated.
+    ///
+    /// Only whole filesystem blocks are released, once the reader has moved past them; the
+    /// destination keeps its length. The reader has its own position, from the start of the
+    /// destination, and doesn't affect the `Sponge`'s.
+    ///
+    /// Releasing storage needs `fallocate(FALLOC_FL_PUNCH_HOLE)`, so Linux, and a filesystem
+    /// which supports it (ext4, xfs, btrfs, tmpfs). Elsewhere, the reader just reads, and
+    /// the destination is untouched, as if this were an ordinary `File::open`. If releasing
+    /// storage fails for any other reason, the read which triggered it still returns its data,
+    /// the error is returned by the next read, and the reader then carries on without releasing
+    /// anything more.
+    pub fn consume_destination(&self) -> Result<ConsumingReader, io::Error> {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.target()?)?;
+
+        #[cfg(unix)]
+        let block_size = file.metadata()?.blksize().max(1);
+        #[cfg(not(unix))]
+        let block_size = 4096;
+
+        Ok(ConsumingReader {
+            file,
+            block_size,
+            pos: 0,
+            released: 0,
+            punching: true,
+            error: None,
+        })
+    }
+
+    /// Throw away everything written so far, and start again, keeping the same temporary file
+    /// and destination.
+    ///
+    /// This is cheaper than creating a new `Sponge`, e.g. when retrying the generation of a
+    /// file. The temporary file is truncated, and the write and read positions, and
+    /// [`Sponge::bytes_written`], go back to zero. Options, and metadata applied by
+    /// [`Sponge::lock_metadata`], are kept.
+    ///
+    /// Any buffered writes are written out before being thrown away, so this can fail for the
+    /// same reasons as [`Sponge::flush_buffer`].
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// this fails with [`io::ErrorKind::Unsupported`], as the writes have already happened.
+    pub fn reset(&mut self) -> Result<(), io::Error> {
+        if let Scratch::Direct(_) = self.temp.get_ref() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "can't reset a destination which is written directly",
+            ));
+        }
+
+        self.temp.flush()?;
+        let scratch = self.temp.get_mut();
+        scratch.file().set_len(0)?;
+        scratch.seek(SeekFrom::Start(0))?;
+
+        self.read_pos = 0;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Where the next write will go, including any buffered writes, e.g. for length-prefixed
+    /// formats which need to know the current offset.
+    ///
+    /// Unlike [`Seek::stream_position`] on a buffered writer, this doesn't write out the
+    /// buffer, or need `Seek` imported. Writes only ever go at the end of the buffer, so the
+    /// position is the file's position, plus what's buffered.
+    pub fn stream_position(&mut self) -> Result<u64, io::Error> {
+        let buffered = self.temp.buffer().len() as u64;
+        Ok(self.temp.get_mut().stream_position()? + buffered)
+    }
+
+    /// Write the buffered writes out to the temporary file, without touching the destination,
+    /// which is what `flush()` would normally do, but doesn't, for a `Sponge`.
+    ///
+    /// This is only useful to find out about write errors, such as the disk being full, before
+    /// `commit()`.
+    pub fn flush_buffer(&mut self) -> Result<(), io::Error> {
+        self.temp.flush()
+    }
+
+    /// The total number of bytes accepted by `write` (and `write_vectored`) so far, e.g. for
+    /// progress reporting. This counts bytes which are still buffered, and not yet written to
+    /// the temporary file.
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// Borrow the `Sponge` as something which can only be written to, and read back.
+    ///
+    /// ```rust
+    /// # use std::io::Write;
+    /// fn produce(mut out: impl Write) -> std::io::Result<()> {
+    ///     out.write_all(b"hello")
+    /// }
+    ///
+    /// let mut temp = tempfile_fast::Sponge::new_for("example.txt").unwrap();
+    /// produce(temp.writer()).unwrap();
+    /// temp.commit().unwrap();
+    /// ```
+    pub fn writer(&mut self) -> SpongeWriter<'_> {
+        SpongeWriter { sponge: self }
+    }
+
+    /// Borrow the `Sponge` as a writer which also copies everything written to `other`, e.g. a
+    /// hasher, or a log.
+    ///
+    /// The `Sponge` is written first, and its errors are returned as usual; `other` only sees
+    /// what the `Sponge` accepted, so the two agree. A failure writing to `other` doesn't fail
+    /// the write: `other` is skipped from then on, and the error is returned by
+    /// [`SpongeTee::into_other`].
+    ///
+    /// ```rust
+    /// # use std::io::Write;
+    /// let mut temp = tempfile_fast::Sponge::new_for("example.txt").unwrap();
+    /// let mut copy = Vec::new();
+    /// temp.tee(&mut copy).write_all(b"hello").unwrap();
+    /// temp.commit().unwrap();
+    /// assert_eq!(b"hello", copy.as_slice());
+    /// ```
+    pub fn tee<W: io::Write>(&mut self, other: W) -> SpongeTee<'_, W> {
+        SpongeTee {
+            sponge: self,
+            other,
+            error: None,
+        }
+    }
+
+    /// Check, now, that the destination looks replaceable, so a `commit()` which would be refused
+    /// permission fails before any expensive writes, not after.
+    ///
+    /// This checks that the process may create and remove names in the destination's directory,
+    /// and that the destination isn't immutable or append-only. On Linux, the directory is
+    /// checked with `faccessat(W_OK | X_OK)`, which also catches read-only filesystems;
+    /// elsewhere, a file is created in the directory, and removed again.
+    ///
+    /// This is advisory: anything may change before `commit()`, which can still fail, for
+    /// these or other reasons. For a destination which is written directly (see
+    /// [`SpongeBuilder::allow_non_regular`]), there's nothing to check.
+    pub fn check_writable(&self) -> Result<(), io::Error> {
+        if let Scratch::Direct(_) = self.temp.get_ref() {
+            return Ok(());
+        }
+
+        let dest = self.target()?;
+        let dir = parent_dir(&dest).ok_or_else(|| no_parent(&dest))?;
+
+        linux::check_dir_writable(dir).map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("can't replace files in {dir:?}: {error}"),
+            )
+        })?;
+
+        if let Ok(true) = linux::is_immutable(&dest) {
+            let error = io::Error::from(io::ErrorKind::PermissionDenied);
+            return Err(immutable_error(&dest, error));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the destination is on an overlay filesystem (overlayfs), e.g. inside a
+    /// container, where replacing a file has some extra costs, and quirks.
+    ///
+    /// `commit()` still renames over the destination, which overlayfs handles correctly, and
+    /// atomically, but: if the destination's directory is only in a lower (image) layer, the
+    /// rename first copies the directory up into the upper layer; anyone with the old file open
+    /// keeps seeing the lower layer's copy; and the lower layer's file stays in the image,
+    /// hidden, using space. [`Sponge::commit_in_place_non_atomic`] avoids replacing the file,
+    /// but copies the whole of it up instead, and isn't atomic.
+    ///
+    /// This checks the filesystem the temporary file is on, which is the destination's (unless
+    /// it's from [`Sponge::new_for_with_scratch`]). It's only ever `true` on Linux.
+    pub fn on_overlayfs(&self) -> Result<bool, io::Error> {
+        linux::is_overlayfs(self.temp.get_ref().file())
+    }
+
+    /// Apply the destination's ownership and permissions to the temporary file now, instead of
+    /// at `commit()` time.
+    ///
+    /// This lets you find out about permissions problems before doing any expensive writes.
+    /// `commit()` will not look at the destination's metadata again: if the destination's
+    /// ownership or permissions change between this call and `commit()`, the ones applied here
+    /// are still used.
+    ///
+    /// This does nothing for a destination which is written directly (see
+    /// [`SpongeBuilder::allow_non_regular`]).
+    pub fn lock_metadata(&mut self) -> Result<(), io::Error> {
+        if let Scratch::Temp(temp) = self.temp.get_ref() {
+            copy_metadata(Some(&self.dest), &self.metadata, temp)?;
+        }
+        self.metadata_locked = true;
+        Ok(())
+    }
+
+    /// Write the `Sponge` out to the destination file.
+    ///
+    /// Ownership and permission is preserved, where appropriate for the platform. The permissions
+    /// and ownership are resolved now, using the (absolute) path provided. i.e. changes to the
+    /// destination's file's permissions since the creation of the `Sponge` will be included,
+    /// unless [`Sponge::lock_metadata`] has been called.
+    ///
+    /// The aim is to transfer all ownership and permission information, but not timestamps
+    /// (see [`SpongeBuilder::preserve_times`]). The implementation, and what information is
+    /// transferred, is subject to change in minor versions.
+    ///
+    /// Any buffered writes are written to the temporary file first; if nothing is buffered
+    /// (e.g. after [`Sponge::flush_buffer`]), no write is made.
+    ///
+    /// The file is `flush()`ed correctly, but not `fsync()`'d. The update is atomic against
+    /// anything that happens to the current process, including erroring, panicking, or crashing.
+    ///
+    /// If you need the update to survive power loss, or OS/kernel issues, you should additionally
+    /// follow the platform recommendations for `fsync()`, which may involve calling `fsync()` on
+    /// at least the new file, and probably on the parent directory. Note that this is the same as
+    /// every other file API, but is being called out here as a reminder, if you are building
+    /// certain types of application. [`Sponge::commit_durable`] does this for you.
+    ///
+    /// ## Platform-specific behavior
+    ///
+    /// Metadata:
+    /// * `unix` (including `linux`): At least `chown(uid, gid)` and `chmod(mode_t)`
+    /// * `windows`: At least the `readonly` flag, the `hidden`, `system`, `archive` and
+    ///   `not content indexed` attributes, and the timestamps. An existing destination is
+    ///   replaced with `ReplaceFileW`, which also keeps its ACLs and alternate data streams,
+    ///   falling back to a rename if the filesystem doesn't support that.
+    /// * all: See [`fs::set_permissions`]
+    ///
+    /// ## Error
+    ///
+    /// If any underlying operation fails the system error will be returned directly. This method
+    /// consumes `self`, so these errors are not recoverable. Failing to set the ownership
+    /// information on the temporary file is an error, not ignored, unlike in many implementations.
+    /// Inside a user namespace, the destination's owner may not be mapped (it appears as the
+    /// overflow id, usually `nobody`); this is reported as [`io::ErrorKind::InvalidInput`],
+    /// naming the ids involved.
+    ///
+    /// ## Non-regular files
+    ///
+    /// If the `Sponge` was created with [`SpongeBuilder::allow_non_regular`], and the destination
+    /// was a character device or FIFO, this only flushes the writes to the destination.
+    /// This is not atomic.
+    pub fn commit(self) -> Result<(), io::Error> {
+        self.into_commit_job()?.run()
+    }
+
+    /// **Not atomic, not crash safe:** overwrite the destination's content with what was
+    /// written, keeping its inode, instead of replacing it with a new file.
+    ///
+    /// The destination is truncated, then the new content copied in. Anyone reading it in the
+    /// meantime, or after a crash or error part way through, may see it empty or partially
+    /// written. **None of the guarantees of [`Sponge::commit`] apply.**
+    ///
+    /// This is only for destinations whose inode must not change: other processes holding it
+    /// open, and expecting to see the new content through their file handle, or other hard
+    /// links which must stay linked. An exclusive advisory lock ([`fs::File::lock`]) is held on
+    /// the destination during the copy, so cooperating readers, which take a shared lock, won't
+    /// see a partial file; nobody else is stopped.
+    ///
+    /// The destination is created if it doesn't exist, and keeps its ownership and
+    /// permissions. The copy is a reflink where the filesystem supports it.
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// this is the same as `commit()`.
+    pub fn commit_in_place_non_atomic(self) -> Result<(), io::Error> {
+        let dest = self.target()?;
+        let temp = match self.temp.into_inner() {
+            Ok(Scratch::Temp(temp)) => temp,
+            Ok(Scratch::Direct(mut file)) => return file.flush(),
+            Err(e) => {
+                let (error, temp) = e.into_parts();
+                return Err(flush_error(error, temp.buffer().len()));
+            }
+        };
+
+        let dest = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dest)?;
+        lock_exclusive(&dest)?;
+        copy::reflink_or_copy(temp.as_ref(), &dest)
+    }
+
+    /// Finish writing, and package up the [`Sponge::commit`] as a job which can be run later,
+    /// possibly on another thread, e.g. by a pool of workers taking jobs from a channel.
+    ///
+    /// The buffered writes are written out to the temporary file now, so an error writing them
+    /// is returned here, rather than from the job, and the `Sponge` is discarded. Nothing is
+    /// committed until the job is [`run`](CommitJob::run); dropping it instead discards the
+    /// `Sponge`, like dropping the `Sponge` would.
+    pub fn into_commit_job(mut self) -> Result<CommitJob, io::Error> {
+        if let Err(error) = self.temp.flush() {
+            return Err(flush_error(error, self.temp.buffer().len()));
+        }
+        Ok(CommitJob { sponge: self })
+    }
+
+    /// Give up on the destination, and hand over the temporary file, with everything written,
+    /// as a [`tempfile::NamedTempFile`], e.g. for a library which only accepts those.
+    ///
+    /// The `Linux` fast path's temporary file has no name, so it's given a random, hidden, one
+    /// first, next to the destination (or in the scratch directory, for
+    /// [`SpongeBuilder::new_for_with_scratch`]). The `Sponge` is returned, unchanged, for a
+    /// destination which is written directly, or if the buffered writes can't be written out,
+    /// or the name can't be made.
+    #[allow(clippy::result_large_err)]
+    pub fn try_into_named_temp(mut self) -> Result<tempfile::NamedTempFile, Sponge> {
+        let dir = match self.scratch_dir {
+            Some(ref dir) => dir.clone(),
+            None => match parent_dir(&self.dest) {
+                Some(dir) => dir.to_path_buf(),
+                None => return Err(self),
+            },
+        };
+
+        if self.temp.flush().is_err() {
+            return Err(self);
+        }
+
+        let temp = match self.temp.into_parts().0 {
+            Scratch::Temp(temp) => temp,
+            scratch => {
+                self.temp = io::BufWriter::new(scratch);
+                return Err(self);
+            }
+        };
+
+        match temp {
+            PersistableTempFile::Fallback(named) => Ok(named),
+            PersistableTempFile::Linux(file) => match name_in(&file, &dir) {
+                Ok(named) => Ok(named),
+                Err(_) => {
+                    self.temp = io::BufWriter::new(Scratch::Temp(PersistableTempFile::Linux(file)));
+                    Err(self)
+                }
+            },
+        }
+    }
+
+    /// Let `verify` check what's been written, e.g. against a digest of what was meant to be
+    /// written, then, only if it returns `true`, [`Sponge::commit`].
+    ///
+    /// The buffered writes are written out, and `verify` is given the temporary file, positioned
+    /// at the start, to read. If it returns `false`, this fails with
+    /// [`io::ErrorKind::InvalidData`], and the `Sponge` is dropped without touching the
+    /// destination. Errors from `verify` are returned as they are, also without committing.
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// there's nothing to read back, so this fails with [`io::ErrorKind::Unsupported`].
+    pub fn commit_verified<F>(mut self, verify: F) -> Result<(), io::Error>
+    where
+        F: FnOnce(&mut fs::File) -> Result<bool, io::Error>,
+    {
+        if let Scratch::Direct(_) = self.temp.get_ref() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "can't verify a destination which is written directly",
+            ));
+        }
+
+        if let Err(error) = self.temp.flush() {
+            return Err(flush_error(error, self.temp.buffer().len()));
+        }
+
+        let file = self.temp.get_mut().file_mut();
+        file.seek(SeekFrom::Start(0))?;
+        if !verify(file)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("verification failed, so {:?} was not replaced", self.dest),
+            ));
+        }
+
+        self.commit()
+    }
+
+    /// [`Sponge::commit`], but only if the destination hasn't changed since the `Sponge` was
+    /// created: compare-and-swap, for files.
+    ///
+    /// The destination's length and modification time (and, on `unix`, its device and inode)
+    /// are recorded by [`Sponge::new_for`], and checked again just before the temporary file is
+    /// renamed into place. If they differ, or the destination has appeared or disappeared, this
+    /// fails with a [`DestinationChanged`] error (of kind [`io::ErrorKind::Other`]), and the
+    /// `Sponge` is dropped without touching the destination:
+    ///
+    /// ```rust
+    /// # use std::io::Write;
+    /// let mut sponge = tempfile_fast::Sponge::new_for("shared.txt").unwrap();
+    /// sponge.write_all(b"mine").unwrap();
+    /// std::fs::write("shared.txt", b"theirs").unwrap();
+    ///
+    /// let error = sponge.commit_if_unchanged().unwrap_err();
+    /// assert!(error
+    ///     .get_ref()
+    ///     .is_some_and(|e| e.is::<tempfile_fast::DestinationChanged>()));
+    /// # std::fs::remove_file("shared.txt").unwrap();
+    /// ```
+    ///
+    /// This detects another process' change, it doesn't lock them out: a change made between
+    /// the check and the rename is still lost. Modification times are only as precise as the
+    /// filesystem, so a same-length change made very soon after the `Sponge` was created may be
+    /// missed, too.
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// the writes have already happened, so this fails with [`io::ErrorKind::Unsupported`].
+    pub fn commit_if_unchanged(mut self) -> Result<(), io::Error> {
+        if let Scratch::Direct(_) = self.temp.get_ref() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "can't check a destination which is written directly",
+            ));
+        }
+
+        self.require_unchanged = true;
+        self.commit()
+    }
+
+    /// [`Sponge::commit`], returning some details of how it went, e.g. for metrics.
+    pub fn commit_with_stats(self) -> Result<CommitStats, io::Error> {
+        self.commit_inner(Durability::None, |_| Ok(()))
+            .map(|committed| committed.stats)
+    }
+
+    /// [`Sponge::commit`], then also give the new file each of the names in `dests`, e.g. a
+    /// versioned name, and `latest`. All the names end up as hard links to the same new file,
+    /// with the ownership and permissions copied from the `Sponge`'s destination.
+    ///
+    /// Each name is replaced atomically, as by [`publish_hardlink`], in order, after the
+    /// destination. They aren't all replaced at once: a reader may see some names updated,
+    /// and others not yet. If one fails, the error says which, and how many were already
+    /// replaced; those stay replaced (there's nothing to roll back to), and the rest are left
+    /// alone. All the names must be on the destination's filesystem.
+    ///
+    /// The names are linked to the file which was committed, not to whatever the destination
+    /// is by then: if someone else replaces it in the meantime, on Linux, the names still get
+    /// this `Sponge`'s file; elsewhere, linking fails with a [`DestinationChanged`].
+    ///
+    /// With a scratch directory on another filesystem (see [`Sponge::new_for_with_scratch`]),
+    /// the data would be copied, and the copy isn't the file this would link, so this fails
+    /// with [`io::ErrorKind::CrossesDevices`], without committing.
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// this fails with [`io::ErrorKind::Unsupported`], without committing.
+    pub fn commit_to_many(self, dests: &[&Path]) -> Result<(), io::Error> {
+        // a handle on the new file, to link it even if the destination is replaced meanwhile
+        let committed = match self.temp.get_ref() {
+            Scratch::Temp(temp) => temp.as_ref().try_clone()?,
+            Scratch::Direct(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "can't link a destination which is written directly",
+                ))
+            }
+        };
+
+        let src = self.target()?;
+
+        // a copy across filesystems would leave `committed` as the scratch file, not the new one
+        if self.scratch_dir.is_some() {
+            check_same_device(&committed, &src).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("can't link other names to a copy from the scratch directory: {e}"),
+                )
+            })?;
+        }
+
+        self.commit()?;
+
+        for (done, dest) in dests.iter().enumerate() {
+            publish_committed(&committed, &src, dest).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "{src:?} was replaced, and {done} of {} other names, \
+                         but linking it to {dest:?} failed: {e}",
+                        dests.len()
+                    ),
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Where this `Sponge`'s writes are going: the `Linux` fast path's anonymous file, the
+    /// `Fallback`'s named temporary file, or directly to the destination.
+    pub fn backend(&self) -> Backend {
+        match self.temp.get_ref() {
+            Scratch::Temp(PersistableTempFile::Linux(_)) => Backend::Linux,
+            Scratch::Temp(PersistableTempFile::Fallback(_)) => Backend::Fallback,
+            Scratch::Direct(_) => Backend::Direct,
+        }
+    }
+
+    /// [`Sponge::commit`], returning a description of the file which was written, e.g. for an
+    /// audit log.
+    ///
+    /// The description is taken from the temporary file just before it replaced the destination,
+    /// so describes exactly what was written, without looking at the destination again.
+    pub fn commit_audited(self) -> Result<CommitRecord, io::Error> {
+        let committed = self.commit_inner(Durability::None, |file| file.metadata())?;
+        Ok(CommitRecord::new(
+            committed.dest,
+            &committed.inspected,
+            committed.fast_path,
+        ))
+    }
+
+    /// Write the `Sponge` out to the destination file, and wait for it to reach stable storage.
+    ///
+    /// This is [`Sponge::commit`], with `fsync()` calls in the places the platform needs them
+    /// for the update to survive power loss. The operations happen in this order:
+    ///
+    ///  1. the buffered writes are flushed to the temporary file,
+    ///  2. the temporary file's data is synced (`fdatasync`),
+    ///  3. the ownership and permissions are applied,
+    ///  4. the temporary file is fully synced (`fsync`), including the metadata,
+    ///  5. the temporary file is renamed over the destination,
+    ///  6. the destination's parent directory is synced, making the rename durable.
+    ///
+    /// Step 4 must be a full `fsync`: `fdatasync` is not required to persist the ownership and
+    /// permissions, so a crash after the rename became durable could otherwise leave the
+    /// new content visible with the wrong owner or mode.
+    ///
+    /// On non-`unix` platforms, the directory is not synced, as there's no portable way to do so.
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// this is the same as `commit()`.
+    pub fn commit_durable(self) -> Result<(), io::Error> {
+        self.commit_inner(Durability::FileAndParent, |_| Ok(()))
+            .map(|_| ())
+    }
+
+    /// [`Sponge::commit_durable`], but, if `dir` is provided, the parent directory is *not*
+    /// synced: the caller is responsible for syncing it later.
+    ///
+    /// This allows many files in the same directory to be committed, then the directory synced
+    /// once, instead of once per file. The commits are not durable until the directory has been
+    /// synced, e.g. with `fsync(dir)`, or [`fs::File::sync_all`] on the directory.
+    ///
+    /// `dir` must be an open handle to the destination's parent directory; this is checked,
+    /// and an [`io::ErrorKind::InvalidInput`] error is returned (before anything is renamed) if
+    /// it isn't. If `dir` is `None`, this is the same as `commit_durable()`.
+    #[cfg(unix)]
+    pub fn commit_durable_in(self, dir: Option<std::os::fd::BorrowedFd>) -> Result<(), io::Error> {
+        match dir {
+            Some(dir) => {
+                check_is_parent(dir, &self.target()?)?;
+                self.commit_inner(Durability::File, |_| Ok(())).map(|_| ())
+            }
+            None => self
+                .commit_inner(Durability::FileAndParent, |_| Ok(()))
+                .map(|_| ()),
+        }
+    }
+
+    /// Flush the buffered writes, and sync the temporary file to stable storage (`fsync`),
+    /// without touching the destination.
+    ///
+    /// This is about media durability, not crash recovery: on the fast Linux path, the temporary
+    /// file has no name, so, if the process (or machine) crashes after a checkpoint, the data is
+    /// lost anyway. It does mean that a much later `commit()` has less work to do, and that
+    /// write errors from the device are reported now.
+    pub fn checkpoint(&mut self) -> Result<(), io::Error> {
+        self.temp.flush()?;
+        self.temp.get_ref().file().sync_all()
+    }
+
+    /// [`Sponge::commit_durable`], then release the `barrier`, letting anyone waiting in
+    /// [`Sponge::commit_after`] continue.
+    ///
+    /// The barrier is only released once the new content is at the destination, and both it
+    /// and the rename (the directory) have reached stable storage. If the commit fails, the
+    /// barrier is still released, but the waiting commits will fail.
+    pub fn commit_before(self, barrier: &Barrier) -> Result<(), io::Error> {
+        let result = self.commit_durable();
+        barrier.release(&result);
+        result
+    }
+
+    /// Wait for the `barrier` to be released by another `Sponge`'s [`Sponge::commit_before`],
+    /// then `commit()`.
+    ///
+    /// This blocks forever if the barrier is never released. If the other `Sponge`'s commit
+    /// failed, this returns an error, and the `Sponge` is dropped without being committed.
+    pub fn commit_after(self, barrier: &Barrier) -> Result<(), io::Error> {
+        barrier.wait()?;
+        self.commit()
+    }
+
+    /// [`Sponge::commit`], but, on failure, the `Sponge` is handed back, along with the error,
+    /// so nothing written is lost, and the commit can be retried, e.g. after freeing some disk
+    /// space.
+    ///
+    /// If the buffered writes can't be written to the temporary file, whatever couldn't be
+    /// written is still buffered in the returned `Sponge`. If the replace itself fails, the
+    /// temporary file is kept, and will be used by the next attempt.
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// this is the same as `commit()`.
+    #[allow(clippy::result_large_err)]
+    pub fn try_commit(self) -> Result<(), (io::Error, Sponge)> {
+        self.try_commit_inner(Durability::None, false, |_| Ok(()))
+            .map(drop)
+    }
+
+    /// Write the `Sponge` out to the destination file, but only if the destination doesn't
+    /// already exist.
+    ///
+    /// This is atomic: if the destination appears in the meantime, it is not overwritten.
+    /// The ownership and permissions are never copied from an existing destination, which is
+    /// going to be refused; only from [`Sponge::new_for_like`]'s template.
+    ///
+    /// ## Error
+    ///
+    /// On any failure, the `Sponge` is handed back, along with the error, so it can be retried,
+    /// as for [`Sponge::try_commit`]. In particular, if the destination already exists, the
+    /// error is [`io::ErrorKind::AlreadyExists`]: the existing file can be read, the `Sponge`
+    /// rewritten, and the commit tried again, giving compare-and-swap style updates.
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// this is the same as `commit()`.
+    #[allow(clippy::result_large_err)]
+    pub fn try_commit_noclobber(self) -> Result<(), (io::Error, Sponge)> {
+        self.try_commit_inner(Durability::None, true, |_| Ok(()))
+            .map(drop)
+    }
+
+    /// Write the `Sponge` out to the destination file, only if it doesn't already exist,
+    /// returning whether it was written, e.g. to write a default configuration file, leaving
+    /// any existing one alone.
+    ///
+    /// This is [`Sponge::try_commit_noclobber`], but an existing destination is `Ok(false)`,
+    /// not an error, and the `Sponge` is dropped, whatever happens.
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// this is the same as `commit()`, so returns `true`.
+    pub fn commit_noclobber(self) -> Result<bool, io::Error> {
+        match self.try_commit_noclobber() {
+            Ok(()) => Ok(true),
+            Err((ref error, _)) if io::ErrorKind::AlreadyExists == error.kind() => Ok(false),
+            Err((error, _)) => Err(error),
+        }
+    }
+
+    /// Refuse a write of `len` bytes, if it would take the total over `max_size`.
+    fn check_size(&self, len: usize) -> Result<(), io::Error> {
+        if let Some(limit) = self.max_size {
+            if self.written.saturating_add(len as u64) > limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::FileTooLarge,
+                    format!("write would exceed the Sponge's maximum size of {limit} bytes"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The path which will actually be replaced: the destination, or where it links to.
+    fn target(&self) -> Result<PathBuf, io::Error> {
+        if self.follow_symlinks {
+            resolve_symlinks(&self.dest)
+        } else {
+            Ok(self.dest.clone())
+        }
+    }
+
+    /// Commit, calling `inspect` on the new file just before it replaces the destination. Only
+    /// an audit wants to look, as it costs an extra `stat`.
+    fn commit_inner<M>(
+        self,
+        durability: Durability,
+        inspect: impl FnOnce(&fs::File) -> Result<M, io::Error>,
+    ) -> Result<Committed<M>, io::Error> {
+        let committed = self
+            .try_commit_inner(durability, false, inspect)
+            .map_err(|(error, _)| error)?;
+
+        if Durability::FileAndParent == durability {
+            sync_dir_of(&committed.dest)?;
+        }
+
+        Ok(committed)
+    }
+
+    /// Everything a commit does, except syncing the directory, handing back the `Sponge` on
+    /// failure, so the `try_` variants can offer it to be tried again.
+    #[allow(clippy::result_large_err)]
+    fn try_commit_inner<M>(
+        mut self,
+        durability: Durability,
+        noclobber: bool,
+        inspect: impl FnOnce(&fs::File) -> Result<M, io::Error>,
+    ) -> Result<Committed<M>, (io::Error, Sponge)> {
+        let started = Instant::now();
+        let mut stats = CommitStats {
+            backend: self.backend(),
+            linked_directly: false,
+            rename_attempts: 0,
+            copy_fallback: false,
+            replaced: false,
+            bytes_written: self.written,
+            elapsed: Duration::ZERO,
+            permissions_skipped: false,
+        };
+
+        if let Err(error) = self.temp.flush() {
+            let error = flush_error(error, self.temp.buffer().len());
+            return Err((error, self));
+        }
+
+        let dest = match self.target() {
+            Ok(dest) => dest,
+            Err(error) => return Err((error, self)),
+        };
+
+        let inspected = match self.prepare(&dest, durability, noclobber, inspect, &mut stats) {
+            Ok(inspected) => inspected,
+            Err(error) => return Err((error, self)),
+        };
+
+        // the buffer is empty, as we just flushed it
+        let temp = match self.temp.into_parts().0 {
+            Scratch::Temp(temp) => temp,
+            Scratch::Direct(_) => {
+                stats.elapsed = started.elapsed();
+                return Ok(Committed {
+                    stats,
+                    dest,
+                    inspected,
+                    fast_path: false,
+                });
+            }
+        };
+        let fast_path = matches!(temp, PersistableTempFile::Linux(_));
+
+        let mut persist = PersistStats::default();
+
+        let persisted = if noclobber {
+            temp.persist_noclobber_file(&dest, self.scratch_dir.is_some())
+                .map(drop)
+        } else {
+            persist_retrying(
+                temp,
+                &dest,
+                self.sharing_retries,
+                self.scratch_dir.is_some(),
+                &mut persist,
+            )
+        };
+
+        if let Err(persist_error) = persisted {
+            self.temp = io::BufWriter::new(Scratch::Temp(persist_error.file));
+            let error = explain_missing_parent(&dest, persist_error.error);
+            return Err((explain_immutable(&dest, error), self));
+        }
+
+        stats.linked_directly = persist.linked_directly;
+        stats.rename_attempts = persist.attempts;
+        stats.copy_fallback = persist.copied;
+        stats.replaced = persist.replaced;
+        stats.elapsed = started.elapsed();
+        Ok(Committed {
+            stats,
+            dest,
+            inspected,
+            fast_path: fast_path && !persist.copied,
+        })
+    }
+
+    /// Get the flushed temporary file ready to replace `dest`, without moving it: sync it, copy
+    /// the metadata over, and check that it's still fine to replace `dest`.
+    fn prepare<M>(
+        &self,
+        dest: &Path,
+        durability: Durability,
+        noclobber: bool,
+        inspect: impl FnOnce(&fs::File) -> Result<M, io::Error>,
+        stats: &mut CommitStats,
+    ) -> Result<M, io::Error> {
+        let temp = match self.temp.get_ref() {
+            Scratch::Temp(temp) => temp,
+            Scratch::Direct(file) => return inspect(file),
+        };
+
+        let durable = Durability::None != durability;
+
+        if durable {
+            temp.sync_data()?;
+        }
+
+        // an existing destination is about to be refused, so has nothing to give the new file
+        let source = if noclobber { None } else { Some(dest) };
+
+        if !self.metadata_locked {
+            stats.permissions_skipped = !copy_metadata(source, &self.metadata, temp)?;
+        }
+
+        if self.metadata.preserve_times {
+            copy_times(source, &self.metadata, temp)?;
+        }
+
+        if durable {
+            temp.sync_all()?;
+        }
+
+        if self.recreate_parent {
+            if let Some(parent) = parent_dir(dest) {
+                create_dirs(parent, self.dir_mode)?;
+            }
+        }
+
+        if self.scratch_dir.is_none() && self.strict_device {
+            check_same_device(temp.as_ref(), dest)
+                .map_err(|error| explain_missing_parent(dest, error))?;
+        }
+
+        let inspected = inspect(temp.as_ref())?;
+
+        if self.require_unchanged {
+            check_unchanged(dest, &self.original)?;
+        }
+
+        Ok(inspected)
+    }
+}
+
+/// Only the destination, and where the writes are going; never the content.
+impl fmt::Debug for Sponge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sponge")
+            .field("dest", &self.dest)
+            .field("backend", self.temp.get_ref())
+            .finish()
+    }
+}
+
+/// A `Sponge` is a `BufWriter`.
+impl io::Write for Sponge {
+    /// `write` to the intermediate file, without touching the destination.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.check_size(buf.len())?;
+        let written = self.temp.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    /// `write_vectored` to the intermediate file, without touching the destination.
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> Result<usize, io::Error> {
+        self.check_size(bufs.iter().map(|buf| buf.len()).sum())?;
+        let written = self.temp.write_vectored(bufs)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    /// Does nothing: the writes can't be observed until `commit()`, so there's nothing to
+    /// flush them for, and generic code which flushes out of habit shouldn't cost a `write`.
+    /// Use [`Sponge::flush_buffer`] to write out the buffer anyway, e.g. to see any error now.
+    ///
+    /// For a destination which is written directly (see [`SpongeBuilder::allow_non_regular`]),
+    /// the writes are observable, so this does flush.
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match *self.temp.get_ref() {
+            Scratch::Temp(_) => Ok(()),
+            Scratch::Direct(_) => self.temp.flush(),
+        }
+    }
+}
+
+/// Move where the next write goes, e.g. to skip over a range filled by
+/// [`Sponge::clone_range_from_existing`], or to patch up a header.
+///
+/// Buffered writes are written out first. This doesn't affect the read position.
+impl io::Seek for Sponge {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        self.temp.seek(pos)
+    }
+
+    /// [`Sponge::stream_position`], which doesn't write out the buffer.
+    fn stream_position(&mut self) -> Result<u64, io::Error> {
+        Sponge::stream_position(self)
+    }
+}
+
+/// A `Sponge` can read back what has been written to it so far.
+///
+/// Reads have their own position, which starts at the beginning of the file, and is independent
+/// of where writes are happening. Each `read` flushes the buffered writes to the temporary file,
+/// seeks to the read position, reads, then seeks back to where the next write will go, so
+/// interleaved writes continue to append.
+///
+/// Reading from a destination which is written directly (see
+/// [`SpongeBuilder::allow_non_regular`]) will generally fail, as it can't seek.
+impl io::Read for Sponge {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.temp.flush()?;
+        let scratch = self.temp.get_mut();
+
+        let write_pos = scratch.stream_position()?;
+        scratch.seek(SeekFrom::Start(self.read_pos))?;
+        let read = scratch.read(buf);
+        scratch.seek(SeekFrom::Start(write_pos))?;
+
+        let read = read?;
+        self.read_pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl io::Write for SpongeWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.sponge.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> Result<usize, io::Error> {
+        self.sponge.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.sponge.flush()
+    }
+}
+
+/// Reads are the `Sponge`'s reads, sharing its read position.
+impl io::Read for SpongeWriter<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.sponge.read(buf)
+    }
+}
+
+impl io::Seek for SpongeWriter<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        self.sponge.seek(pos)
+    }
+}
+
+impl<W> SpongeTee<'_, W> {
+    /// The other writer, or the error which stopped it being written to.
+    pub fn into_other(self) -> Result<W, io::Error> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.other),
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for SpongeTee<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let written = self.sponge.write(buf)?;
+        if self.error.is_none() {
+            if let Err(error) = self.other.write_all(&buf[..written]) {
+                self.error = Some(error);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.sponge.flush()?;
+        if self.error.is_none() {
+            if let Err(error) = self.other.flush() {
+                self.error = Some(error);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Scratch {
+    fn file(&self) -> &fs::File {
+        match self {
+            Scratch::Temp(temp) => temp.as_ref(),
+            Scratch::Direct(file) => file,
+        }
+    }
+
+    fn file_mut(&mut self) -> &mut fs::File {
+        match self {
+            Scratch::Temp(temp) => temp.as_mut(),
+            Scratch::Direct(file) => file,
+        }
+    }
+}
+
+impl io::Read for Scratch {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.file_mut().read(buf)
+    }
+}
+
+impl io::Seek for Scratch {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        self.file_mut().seek(pos)
+    }
+}
+
+impl io::Write for Scratch {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        match self {
+            Scratch::Temp(temp) => temp.write(buf),
+            Scratch::Direct(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self {
+            Scratch::Temp(temp) => temp.flush(),
+            Scratch::Direct(file) => file.flush(),
+        }
+    }
+}
+
+/// Atomically make `dest` another name for the existing file `src`, replacing anything
+/// already at `dest`, without copying any data.
+///
+/// This is for publishing a file which has already been written somewhere else on the same
+/// filesystem, e.g. in a content-addressed store, under a new name. `src` is hard linked to
+/// a temporary name in `dest`'s directory, which is then renamed over `dest`, so `dest` is
+/// never missing, or partially written.
+///
+/// `src` and `dest` share an inode afterwards, so, unlike a [`Sponge`], the ownership and
+/// permissions are `src`'s, and later changes to either are visible through both. If `dest`
+/// is already a link to `src`, nothing changes.
+pub fn publish_hardlink<S: AsRef<Path>, D: AsRef<Path>>(src: S, dest: D) -> Result<(), io::Error> {
+    let src = src.as_ref();
+    let dest = dest.as_ref();
+    let dir = parent_dir(dest).ok_or_else(|| no_parent(dest))?;
 
-        let path = if path.is_absolute() {
-            path.to_path_buf()
-        } else {
-            let mut absolute = env::current_dir()?;
-            absolute.push(path);
-            absolute
+    let temp = random_name(dir, &mut 0, |name| fs::hard_link(src, name))?;
+    replace_with_link(&temp, dest)
+}
+
+/// Give the committed `file`, whose name was `src`, the new name `dest`, as `publish_hardlink`
+/// does, without trusting that `src` is still the same file.
+fn publish_committed(file: &fs::File, src: &Path, dest: &Path) -> Result<(), io::Error> {
+    let dir = parent_dir(dest).ok_or_else(|| no_parent(dest))?;
+    let temp = random_name(dir, &mut 0, |name| link_committed(file, src, name))?;
+    replace_with_link(&temp, dest)
+}
+
+/// Link the open `file` itself, not whatever `src` names now, to `name`.
+#[cfg(target_os = "linux")]
+fn link_committed(file: &fs::File, _src: &Path, name: &Path) -> Result<(), io::Error> {
+    linux::link_at(file, name)
+}
+
+/// There's no linking an open file elsewhere, so link `src`, then check that it was `file`.
+#[cfg(not(target_os = "linux"))]
+fn link_committed(file: &fs::File, src: &Path, name: &Path) -> Result<(), io::Error> {
+    fs::hard_link(src, name)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let linked = fs::symlink_metadata(name)?;
+        let committed = file.metadata()?;
+        if linked.dev() != committed.dev() || linked.ino() != committed.ino() {
+            fs::remove_file(name)?;
+            return Err(io::Error::other(DestinationChanged {
+                dest: src.to_path_buf(),
+            }));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = file;
+    Ok(())
+}
+
+/// Rename `temp`, a new link, over `dest`, tidying up `temp` if that did nothing.
+fn replace_with_link(temp: &Path, dest: &Path) -> Result<(), io::Error> {
+    if let Err(e) = fs::rename(temp, dest) {
+        let _ = fs::remove_file(temp);
+        return Err(e);
+    }
+
+    // renaming a name over another link to the same file does nothing, leaving `temp` behind;
+    // otherwise, `temp` is gone, and anything there now isn't ours to remove
+    if is_same_file(temp, dest) {
+        fs::remove_file(temp).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("couldn't remove temporary link {temp:?}: {e}"),
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Whether `a` and `b` both exist, and are names for the same file.
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Renaming over another link to the same file does replace it on Windows.
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// `persist_by_rename`, retrying while the destination is in use by another process. If `copy`,
+/// the data is copied if the destination is on another filesystem.
+fn persist_retrying(
+    mut temp: PersistableTempFile,
+    dest: &Path,
+    retries: u32,
+    copy: bool,
+    stats: &mut PersistStats,
+) -> Result<(), PersistError> {
+    let mut options = RenameOptions::default();
+    options.copy_across_filesystems(copy);
+
+    let mut delay = Duration::from_millis(10);
+    let mut attempt = 0;
+    loop {
+        let persist_error = match replace_or_rename(temp, dest, &mut options, stats) {
+            Ok(()) => return Ok(()),
+            Err(persist_error) => persist_error,
         };
 
-        let parent = path
-            .parent()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "path must have a parent"))?;
+        if 0 == retries || !is_sharing_violation(&persist_error.error) {
+            return Err(persist_error);
+        }
 
-        fs::create_dir_all(parent)?;
+        if attempt == retries {
+            return Err(PersistError {
+                error: io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    SharingViolation {
+                        dest: dest.to_path_buf(),
+                        retries,
+                        error: persist_error.error,
+                    },
+                ),
+                ..persist_error
+            });
+        }
 
-        Ok(Sponge {
-            temp: io::BufWriter::new(PersistableTempFile::new_in(parent)?),
-            dest: path,
-        })
+        attempt += 1;
+        temp = persist_error.file;
+        thread::sleep(delay);
+        delay *= 2;
     }
+}
 
-    /// Write the `Sponge` out to the destination file.
-    ///
-    /// Ownership and permission is preserved, where appropriate for the platform. The permissions
-    /// and ownership are resolved now, using the (absolute) path provided. i.e. changes to the
-    /// destination's file's permissions since the creation of the `Sponge` will be included.
-    ///
-    /// The aim is to transfer all ownership and permission information, but not timestamps.
-    /// The implementation, and what information is transferred, is subject to change in minor
-    /// versions.
-    ///
-    /// The file is `flush()`ed correctly, but not `fsync()`'d. The update is atomic against
-    /// anything that happens to the current process, including erroring, panicking, or crashing.
-    ///
-    /// If you need the update to survive power loss, or OS/kernel issues, you should additionally
-    /// follow the platform recommendations for `fsync()`, which may involve calling `fsync()` on
-    /// at least the new file, and probably on the parent directory. Note that this is the same as
-    /// every other file API, but is being called out here as a reminder, if you are building
-    /// certain types of application.
-    ///
-    /// ## Platform-specific behavior
-    ///
-    /// Metadata:
-    /// * `unix` (including `linux`): At least `chown(uid, gid)` and `chmod(mode_t)`
-    /// * `windows`: At least the `readonly` flag.
-    /// * all: See [`fs::set_permissions`]
-    ///
-    /// ## Error
-    ///
-    /// If any underlying operation fails the system error will be returned directly. This method
-    /// consumes `self`, so these errors are not recoverable. Failing to set the ownership
-    /// information on the temporary file is an error, not ignored, unlike in many implementations.
-    pub fn commit(self) -> Result<(), io::Error> {
-        let temp = self.temp.into_inner()?;
-        copy_metadata(&self.dest, temp.as_ref())?;
-        temp.persist_by_rename(self.dest)
-            .map_err(|persist_error| persist_error.error)?;
-        Ok(())
+/// Put `temp` in place of `dest`: by renaming it over `dest`, or, on Windows, by replacing an
+/// existing `dest`, which keeps the parts of it we can't copy across, like its ACLs.
+#[cfg(windows)]
+fn replace_or_rename(
+    temp: PersistableTempFile,
+    dest: &Path,
+    options: &mut RenameOptions,
+    stats: &mut PersistStats,
+) -> Result<(), PersistError> {
+    match windows_replace::replace(temp, dest)? {
+        Some(temp) => temp.persist_by_rename_stats(dest, options, stats),
+        None => {
+            stats.replaced = true;
+            Ok(())
+        }
     }
 }
 
-/// A `Sponge` is a `BufWriter`.
-impl io::Write for Sponge {
-    /// `write` to the intermediate file, without touching the destination.
-    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-        self.temp.write(buf)
+#[cfg(not(windows))]
+fn replace_or_rename(
+    temp: PersistableTempFile,
+    dest: &Path,
+    options: &mut RenameOptions,
+    stats: &mut PersistStats,
+) -> Result<(), PersistError> {
+    temp.persist_by_rename_stats(dest, options, stats)
+}
+
+/// Fail if `dest` isn't as it was when the `Sponge` was created, as described by `original`.
+fn check_unchanged(dest: &Path, original: &Option<Snapshot>) -> Result<(), io::Error> {
+    let now = match dest.metadata() {
+        Ok(metadata) => Some(Snapshot::of(&metadata)),
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => None,
+        Err(e) => return Err(e),
+    };
+
+    if now != *original {
+        return Err(io::Error::other(DestinationChanged {
+            dest: dest.to_path_buf(),
+        }));
     }
 
-    /// `flush` to the intermediate file, without touching the destination.
-    /// This has no real purpose, as these writes should not be observable.
-    fn flush(&mut self) -> Result<(), io::Error> {
-        self.temp.flush()
+    Ok(())
+}
+
+/// Explain that `error` happened while writing out the buffer, before anything was replaced.
+fn flush_error(error: io::Error, unwritten: usize) -> io::Error {
+    io::Error::new(
+        error.kind(),
+        format!(
+            "couldn't write buffered data to the temporary file ({unwritten} bytes unwritten): {error}"
+        ),
+    )
+}
+
+/// The destination couldn't be replaced, because it is open in another process.
+#[derive(Debug)]
+struct SharingViolation {
+    dest: PathBuf,
+    retries: u32,
+    error: io::Error,
+}
+
+impl fmt::Display for SharingViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} is in use by another process, after {} retries: {}",
+            self.dest, self.retries, self.error
+        )
+    }
+}
+
+impl error::Error for SharingViolation {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[cfg(windows)]
+fn is_sharing_violation(error: &io::Error) -> bool {
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    // from `ReplaceFileW`, if the destination is open without `FILE_SHARE_DELETE`
+    const ERROR_UNABLE_TO_REMOVE_REPLACED: i32 = 1175;
+
+    matches!(
+        error.raw_os_error(),
+        Some(ERROR_ACCESS_DENIED)
+            | Some(ERROR_SHARING_VIOLATION)
+            | Some(ERROR_UNABLE_TO_REMOVE_REPLACED)
+    )
+}
+
+#[cfg(not(windows))]
+fn is_sharing_violation(_error: &io::Error) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn check_same_device(temp: &fs::File, dest: &Path) -> Result<(), io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let parent = match parent_dir(dest) {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+
+    if temp.metadata()?.dev() != parent.metadata()?.dev() {
+        return Err(io::Error::new(
+            io::ErrorKind::CrossesDevices,
+            format!(
+                "temporary file is not on the same device as {:?}; is a mount point involved?",
+                parent
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_same_device(_temp: &fs::File, _dest: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Fail, explaining why, if `path` is on a drive (or share) which isn't there, instead of the
+/// vague "path not found" from creating the directories, or the temporary file, on it.
+#[cfg(windows)]
+fn check_volume(path: &Path) -> Result<(), io::Error> {
+    use std::path::Component;
+
+    let mut root = match path.components().next() {
+        Some(Component::Prefix(prefix)) => PathBuf::from(prefix.as_os_str()),
+        _ => return Ok(()),
+    };
+    root.push("\\");
+
+    match fs::metadata(&root) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("the volume {root:?}, for {path:?}, is not available: {e}"),
+        )),
+    }
+}
+
+fn absolute(path: &Path) -> Result<PathBuf, io::Error> {
+    Ok(if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        let mut absolute = env::current_dir()?;
+        absolute.push(path);
+        absolute
+    })
+}
+
+/// Follow `path`, while it is a symlink, to whatever it finally points at, which may not exist.
+fn resolve_symlinks(path: &Path) -> Result<PathBuf, io::Error> {
+    let mut path = path.to_path_buf();
+
+    // the same limit as Linux's `ELOOP`
+    for _ in 0..40 {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => (),
+            Ok(_) => return Ok(path),
+            Err(ref e) if io::ErrorKind::NotFound == e.kind() => return Ok(path),
+            Err(e) => return Err(e),
+        }
+
+        let target = fs::read_link(&path)?;
+        // an absolute target replaces the whole path
+        path = match path.parent() {
+            Some(parent) => parent.join(target),
+            None => target,
+        };
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("too many levels of symbolic links, resolving {path:?}"),
+    ))
+}
+
+/// The directory containing `path`, which is the current directory for a bare relative name.
+fn parent_dir(path: &Path) -> Option<&Path> {
+    path.parent().map(|parent| {
+        if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        }
+    })
+}
+
+/// `create_dir_all`, giving any new directories `mode`, on `unix`.
+fn create_dirs(dir: &Path, mode: Option<u32>) -> Result<(), io::Error> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    builder.create(dir)
+}
+
+/// If `error` is because `dest`'s directory has been removed, say so, instead of the bare
+/// `ENOENT`, which doesn't say what was missing.
+fn explain_missing_parent(dest: &Path, error: io::Error) -> io::Error {
+    if io::ErrorKind::NotFound != error.kind() {
+        return error;
+    }
+
+    match parent_dir(dest) {
+        Some(parent) if matches!(parent.try_exists(), Ok(false)) => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("the destination's directory, {parent:?}, no longer exists: {error}"),
+        ),
+        _ => error,
+    }
+}
+
+/// Name the usual cause of being refused permission to replace a file, even as root.
+fn explain_immutable(dest: &Path, error: io::Error) -> io::Error {
+    if io::ErrorKind::PermissionDenied != error.kind()
+        || !matches!(linux::is_immutable(dest), Ok(true))
+    {
+        return error;
     }
+
+    immutable_error(dest, error)
+}
+
+fn immutable_error(dest: &Path, error: io::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!(
+            "the destination, {dest:?}, is immutable or append-only (see lsattr(1)), \
+             so can't be replaced: {error}"
+        ),
+    )
+}
+
+/// The error for a destination like `/`, which can't be replaced, as it has no directory.
+fn no_parent(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("destination {path:?} has no parent directory to create a file in"),
+    )
+}
+
+/// Open `lock_path` (creating it, if necessary), and wait for an exclusive lock on it.
+fn take_lock(lock_path: &Path) -> Result<fs::File, io::Error> {
+    let lock = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)?;
+    lock_exclusive(&lock)?;
+    Ok(lock)
 }
 
-fn copy_metadata(source: &Path, dest: &fs::File) -> Result<(), io::Error> {
-    let metadata = match source.metadata() {
-        Ok(metadata) => metadata,
-        Err(ref e) if io::ErrorKind::NotFound == e.kind() => {
+/// Wait for an exclusive lock on the whole of `file`, as `File::lock` does, without needing
+/// Rust 1.89.
+#[cfg(unix)]
+fn lock_exclusive(file: &fs::File) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        if 0 == unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
             return Ok(());
         }
-        Err(e) => Err(e)?,
+
+        let error = io::Error::last_os_error();
+        if io::ErrorKind::Interrupted != error.kind() {
+            return Err(error);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn lock_exclusive(file: &fs::File) -> Result<(), io::Error> {
+    windows_lock::lock_exclusive(file)
+}
+
+fn ends_with_separator(path: &Path) -> bool {
+    path.as_os_str()
+        .as_encoded_bytes()
+        .last()
+        .is_some_and(|&b| std::path::is_separator(char::from(b)))
+}
+
+#[cfg(unix)]
+fn is_non_regular(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    file_type.is_char_device() || file_type.is_fifo()
+}
+
+#[cfg(not(unix))]
+fn is_non_regular(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn check_is_parent(dir: std::os::fd::BorrowedFd, path: &Path) -> Result<(), io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = fs::File::from(dir.try_clone_to_owned()?).metadata()?;
+    let parent = match parent_dir(path) {
+        Some(parent) => parent.metadata()?,
+        None => return Err(io::ErrorKind::InvalidInput.into()),
+    };
+
+    if dir.dev() != parent.dev() || dir.ino() != parent.ino() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "directory handle is not the destination's parent",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns `false` if the permissions weren't applied, as `best_effort_permissions` allowed.
+fn copy_metadata(
+    dest_path: Option<&Path>,
+    options: &MetadataOptions,
+    temp: &PersistableTempFile,
+) -> Result<bool, io::Error> {
+    let metadata = match source_metadata(dest_path, options)? {
+        Some(metadata) => metadata,
+        None => {
+            #[cfg(unix)]
+            if options.apply_umask {
+                if let Some(umask) = unix_chown::umask() {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mode = 0o666 & !umask;
+                    temp.as_ref()
+                        .set_permissions(fs::Permissions::from_mode(mode))?;
+                }
+            }
+            return Ok(true);
+        }
     };
 
-    dest.set_permissions(metadata.permissions())?;
+    let dest: &fs::File = temp.as_ref();
 
+    // Ownership first: changing it clears the setuid and setgid bits, which the permissions
+    // then put back.
     #[cfg(unix)]
-    unix_chown::chown(metadata, dest)?;
+    if options.best_effort_group {
+        unix_chown::chown_best_effort(&metadata, dest)?;
+    } else {
+        unix_chown::chown(&metadata, dest)?;
+    }
 
-    Ok(())
+    let applied = match dest.set_permissions(metadata.permissions()) {
+        Ok(()) => true,
+        // only the filesystem, or our privileges, refusing the mode; anything else is real
+        Err(ref e)
+            if options.best_effort_permissions
+                && matches!(
+                    e.kind(),
+                    io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+                ) =>
+        {
+            false
+        }
+        Err(e) => return Err(e),
+    };
+
+    #[cfg(windows)]
+    windows_attributes::copy(&metadata, temp)?;
+
+    Ok(applied)
+}
+
+/// The metadata to copy: the template's, or the destination's, if there is one. `dest_path` is
+/// `None` if the destination isn't to be looked at.
+fn source_metadata(
+    dest_path: Option<&Path>,
+    options: &MetadataOptions,
+) -> Result<Option<fs::Metadata>, io::Error> {
+    let source = match options.template.as_deref().or(dest_path) {
+        Some(source) => source,
+        None => return Ok(None),
+    };
+    match source.metadata() {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Apply the access and modification times of the destination (or template) to `temp`, if
+/// there is one.
+fn copy_times(
+    dest_path: Option<&Path>,
+    options: &MetadataOptions,
+    temp: &PersistableTempFile,
+) -> Result<(), io::Error> {
+    let metadata = match source_metadata(dest_path, options)? {
+        Some(metadata) => metadata,
+        None => return Ok(()),
+    };
+
+    temp.set_times(
+        fs::FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?),
+    )
 }
 
 #[cfg(unix)]
@@ -149,10 +2531,53 @@ mod unix_chown {
     use std::os::unix::fs::MetadataExt;
     use std::os::unix::io::AsRawFd;
 
-    pub fn chown(source: fs::Metadata, dest: &fs::File) -> Result<(), io::Error> {
+    pub fn chown(source: &fs::Metadata, dest: &fs::File) -> Result<(), io::Error> {
+        chown_to(dest, source.uid(), source.gid())
+    }
+
+    /// `chown`, falling back to just the group, then to nothing, if not permitted.
+    pub fn chown_best_effort(source: &fs::Metadata, dest: &fs::File) -> Result<(), io::Error> {
+        let refused = |result: Result<(), io::Error>| match result {
+            Err(ref e) if Some(libc::EPERM) == e.raw_os_error() => Ok(true),
+            other => other.map(|()| false),
+        };
+
+        if !refused(chown(source, dest))? {
+            return Ok(());
+        }
+
+        // `-1`: leave the owner alone
+        let group = zero_success(unsafe { libc::fchown(dest.as_raw_fd(), u32::MAX, source.gid()) });
+        refused(group).map(drop)
+    }
+
+    pub fn chown_to(dest: &fs::File, uid: u32, gid: u32) -> Result<(), io::Error> {
         let fd = dest.as_raw_fd();
-        zero_success(unsafe { libc::fchown(fd, source.uid(), source.gid()) })?;
-        Ok(())
+        zero_success(unsafe { libc::fchown(fd, uid, gid) }).map_err(|e| {
+            // Linux's answer for an id with no mapping into the current user namespace
+            if Some(libc::EINVAL) != e.raw_os_error() {
+                return e;
+            }
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "can't set owner to {uid}:{gid}, which isn't mapped into this user \
+                     namespace (see /proc/self/uid_map and gid_map): {e}"
+                ),
+            )
+        })
+    }
+
+    /// The process' current `umask`, if `/proc` reports it (Linux 4.7 and later).
+    ///
+    /// Otherwise, the only way to read it is to set it, and put it back, which briefly changes
+    /// it for every thread, so it's not read at all.
+    pub fn umask() -> Option<u32> {
+        fs::read_to_string("/proc/self/status")
+            .ok()?
+            .lines()
+            .find_map(|line| line.strip_prefix("Umask:"))
+            .and_then(|mask| u32::from_str_radix(mask.trim(), 8).ok())
     }
 
     fn zero_success(err: libc::c_int) -> Result<(), io::Error> {
@@ -163,3 +2588,212 @@ mod unix_chown {
         Err(io::Error::last_os_error())
     }
 }
+
+#[cfg(windows)]
+mod windows_attributes {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::FileTimesExt;
+    use std::os::windows::fs::MetadataExt;
+
+    use crate::PersistableTempFile;
+
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+    const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x20;
+    const FILE_ATTRIBUTE_NOT_CONTENT_INDEXED: u32 = 0x2000;
+
+    /// The attributes which describe the file, rather than how it is stored.
+    /// `READONLY` is handled by `set_permissions`.
+    const PRESERVED: u32 = FILE_ATTRIBUTE_HIDDEN
+        | FILE_ATTRIBUTE_SYSTEM
+        | FILE_ATTRIBUTE_ARCHIVE
+        | FILE_ATTRIBUTE_NOT_CONTENT_INDEXED;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetFileAttributesW(lpFileName: *const u16, dwFileAttributes: u32) -> i32;
+    }
+
+    /// Apply the original's timestamps and attributes to the temporary file, before it is
+    /// renamed into place, so the new file never appears without them.
+    pub fn copy(source: &fs::Metadata, temp: &PersistableTempFile) -> Result<(), io::Error> {
+        let mut times = fs::FileTimes::new()
+            .set_accessed(source.accessed()?)
+            .set_modified(source.modified()?);
+        if let Ok(created) = source.created() {
+            times = times.set_created(created);
+        }
+        temp.set_times(times)?;
+
+        // The fast path isn't available on Windows, so there's always a name to work with.
+        let named = match *temp {
+            PersistableTempFile::Fallback(ref named) => named,
+            PersistableTempFile::Linux(_) => return Ok(()),
+        };
+
+        let current = named.as_file().metadata()?.file_attributes();
+        let wanted = (current & !PRESERVED) | (source.file_attributes() & PRESERVED);
+        if wanted != current {
+            set_attributes(named.path().as_os_str(), wanted)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_attributes(path: &OsStr, attributes: u32) -> Result<(), io::Error> {
+        let wide: Vec<u16> = path.encode_wide().chain(Some(0)).collect();
+        if 0 == unsafe { SetFileAttributesW(wide.as_ptr(), attributes) } {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod windows_lock {
+    use std::ffi::c_void;
+    use std::fs;
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+
+    const LOCKFILE_EXCLUSIVE_LOCK: u32 = 0x2;
+
+    /// `OVERLAPPED`, with the union of `Offset` and `OffsetHigh`, and `Pointer`, as the former,
+    /// which is at least as large.
+    #[repr(C)]
+    #[allow(non_snake_case)]
+    struct Overlapped {
+        Internal: usize,
+        InternalHigh: usize,
+        Offset: u32,
+        OffsetHigh: u32,
+        hEvent: *mut c_void,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LockFileEx(
+            hFile: *mut c_void,
+            dwFlags: u32,
+            dwReserved: u32,
+            nNumberOfBytesToLockLow: u32,
+            nNumberOfBytesToLockHigh: u32,
+            lpOverlapped: *mut Overlapped,
+        ) -> i32;
+    }
+
+    /// Wait for an exclusive lock on the whole of `file`, from offset `0`, as `File::lock` does.
+    /// It's released when the handle is closed.
+    pub fn lock_exclusive(file: &fs::File) -> Result<(), io::Error> {
+        let mut overlapped = Overlapped {
+            Internal: 0,
+            InternalHigh: 0,
+            Offset: 0,
+            OffsetHigh: 0,
+            hEvent: ptr::null_mut(),
+        };
+
+        let locked = unsafe {
+            LockFileEx(
+                file.as_raw_handle(),
+                LOCKFILE_EXCLUSIVE_LOCK,
+                0,
+                u32::MAX,
+                u32::MAX,
+                &mut overlapped,
+            )
+        };
+
+        if 0 == locked {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod windows_replace {
+    use std::ffi::c_void;
+    use std::ffi::OsStr;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+
+    use crate::PersistError;
+    use crate::PersistStage;
+    use crate::PersistableTempFile;
+
+    const REPLACEFILE_IGNORE_MERGE_ERRORS: u32 = 0x2;
+    const ERROR_INVALID_FUNCTION: i32 = 1;
+    const ERROR_NOT_SUPPORTED: i32 = 50;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn ReplaceFileW(
+            lpReplacedFileName: *const u16,
+            lpReplacementFileName: *const u16,
+            lpBackupFileName: *const u16,
+            dwReplaceFlags: u32,
+            lpExclude: *mut c_void,
+            lpReserved: *mut c_void,
+        ) -> i32;
+    }
+
+    /// Put `temp` in place of the existing file at `dest` with `ReplaceFileW`, which, unlike a
+    /// rename (`MoveFileEx`), keeps the original's ACLs, owner, and alternate data streams.
+    ///
+    /// Returns the file back, for the usual rename, if there's no existing file to replace, or
+    /// if the destination is on a filesystem which doesn't support `ReplaceFileW`. Any other
+    /// failure is returned, with the file, as for a failed rename.
+    ///
+    /// The replacement is left open: like `NamedTempFile::persist`'s rename, this relies on
+    /// `tempfile` opening it with `FILE_SHARE_DELETE`.
+    pub fn replace(
+        temp: PersistableTempFile,
+        dest: &Path,
+    ) -> Result<Option<PersistableTempFile>, PersistError> {
+        let mut named = match temp {
+            PersistableTempFile::Fallback(named) if dest.is_file() => named,
+            other => return Ok(Some(other)),
+        };
+
+        let replaced = unsafe {
+            ReplaceFileW(
+                wide(dest.as_os_str()).as_ptr(),
+                wide(named.path().as_os_str()).as_ptr(),
+                ptr::null(),
+                REPLACEFILE_IGNORE_MERGE_ERRORS,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+
+        if 0 != replaced {
+            // The temporary name is gone; there's nothing left to clean up.
+            named.disable_cleanup(true);
+            return Ok(None);
+        }
+
+        let error = io::Error::last_os_error();
+        let temp = PersistableTempFile::Fallback(named);
+        match error.raw_os_error() {
+            Some(ERROR_INVALID_FUNCTION) | Some(ERROR_NOT_SUPPORTED) => Ok(Some(temp)),
+            _ => Err(PersistError {
+                error,
+                file: temp,
+                stage: PersistStage::Rename,
+                dest: None,
+            }),
+        }
+    }
+
+    fn wide(path: &OsStr) -> Vec<u16> {
+        path.encode_wide().chain(Some(0)).collect()
+    }
+}
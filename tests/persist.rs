@@ -8,7 +8,9 @@ use std::io::SeekFrom;
 use std::io::Write;
 
 use tempfile_fast::PersistError;
+use tempfile_fast::PersistStage;
 use tempfile_fast::PersistableTempFile;
+use tempfile_fast::RenameOptions;
 
 #[test]
 fn empty_on_linux() {
@@ -16,18 +18,25 @@ fn empty_on_linux() {
         .prefix("tempfile-deleted")
         .tempdir()
         .unwrap();
-    let tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
 
     // Will only actually be deleted on (modern) linux:
     #[cfg(target_os = "linux")]
     {
         assert_eq!(0, fs::read_dir(&temp_dir).unwrap().count());
+        assert_eq!(0, tmp.link_count().unwrap());
     }
 
     let dest = temp_dir.path().to_path_buf().join("foo");
 
-    tmp.persist_noclobber(&dest).unwrap();
+    let persisted = tmp.persist_noclobber_open(&dest).unwrap();
     assert!(dest.exists());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(1, persisted.metadata().unwrap().nlink());
+    }
+    drop(persisted);
 }
 
 #[test]
@@ -52,6 +61,7 @@ fn overwrite() {
         Ok(()) => unreachable!(),
         Err(e) => {
             let e_checked: PersistError = e;
+            assert_eq!(PersistStage::Link, e_checked.stage);
             e_checked.file
         }
     };
@@ -105,3 +115,935 @@ fn read_write() {
     assert_eq!(0, tmp.seek(SeekFrom::Start(0)).unwrap());
     assert_eq!("hihi", read(tmp));
 }
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn large_buffered_write_owned() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let expected = pattern(1024 * 1024 + 7);
+
+    let tmp = PersistableTempFile::new_in(temp_dir.path()).unwrap();
+    let mut writer = std::io::BufWriter::with_capacity(7, tmp);
+    writer.write_all(&expected).unwrap();
+    let mut tmp = writer.into_inner().unwrap();
+
+    assert_eq!(0, tmp.seek(SeekFrom::Start(0)).unwrap());
+    let mut actual = Vec::new();
+    tmp.read_to_end(&mut actual).unwrap();
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn large_buffered_write_borrowed() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let expected = pattern(1024 * 1024 + 7);
+
+    let tmp = PersistableTempFile::new_in(temp_dir.path()).unwrap();
+    {
+        let mut writer = std::io::BufWriter::with_capacity(7, &tmp);
+        writer.write_all(&expected).unwrap();
+        writer.flush().unwrap();
+    }
+
+    let mut reader = &tmp;
+    assert_eq!(0, reader.seek(SeekFrom::Start(0)).unwrap());
+    let mut actual = Vec::new();
+    let mut buf = [0u8; 13];
+    loop {
+        match reader.read(&mut buf).unwrap() {
+            0 => break,
+            n => actual.extend_from_slice(&buf[..n]),
+        }
+    }
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn open_flags() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+
+    match tmp {
+        PersistableTempFile::Linux(_) => {
+            #[cfg(target_os = "linux")]
+            {
+                let flags = tmp.open_flags().unwrap();
+                assert_eq!(libc::O_TMPFILE, flags & libc::O_TMPFILE);
+                assert_eq!(libc::O_RDWR, flags & libc::O_ACCMODE);
+                assert_eq!(libc::O_CLOEXEC, flags & libc::O_CLOEXEC);
+            }
+        }
+        PersistableTempFile::Fallback(_) => assert_eq!(None, tmp.open_flags()),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn persist_in_place_keeps_inode() {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+    fs::write(&dest, b"some longer original content").unwrap();
+    let inode = fs::metadata(&dest).unwrap().ino();
+
+    let mut tmp = PersistableTempFile::new_in(temp_dir.path()).unwrap();
+    tmp.write_all(b"replaced").unwrap();
+    tmp.persist_in_place(&dest).unwrap();
+
+    assert_eq!(inode, fs::metadata(&dest).unwrap().ino());
+    assert_eq!(8, fs::metadata(&dest).unwrap().len());
+    assert_eq!(b"replaced", fs::read(&dest).unwrap().as_slice());
+    assert_eq!(1, fs::read_dir(temp_dir.path()).unwrap().count());
+}
+
+#[test]
+fn rename_stage() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dir");
+    fs::create_dir(&dest).unwrap();
+    fs::File::create(dest.join("occupied")).unwrap();
+
+    let tmp = PersistableTempFile::new_in(temp_dir.path()).unwrap();
+    let e = tmp.persist_by_rename(&dest).unwrap_err();
+    assert_eq!(PersistStage::Rename, e.stage);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn custom_flags() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+
+    let tmp = tempfile_fast::Builder::new()
+        .custom_flags(libc::O_NOATIME)
+        .tempfile_in(&temp_dir)
+        .unwrap();
+    if let Some(flags) = tmp.open_flags() {
+        assert_eq!(libc::O_NOATIME, flags & libc::O_NOATIME);
+        assert_eq!(libc::O_RDWR, flags & libc::O_ACCMODE);
+    }
+
+    let tmp = tempfile_fast::Builder::new()
+        .noatime(true)
+        .tempfile_in(&temp_dir)
+        .unwrap();
+    if let Some(flags) = tmp.open_flags() {
+        assert_eq!(libc::O_NOATIME, flags & libc::O_NOATIME);
+    }
+
+    let e = tempfile_fast::Builder::new()
+        .custom_flags(libc::O_WRONLY)
+        .tempfile_in(&temp_dir)
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, e.kind());
+}
+
+#[test]
+fn probe_link() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-probe")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+
+    let tmp = tempfile_fast::Builder::new()
+        .probe_link(true)
+        .tempfile_in(&temp_dir)
+        .unwrap();
+    // the probe leaves nothing behind
+    assert_eq!(0, fs::read_dir(&temp_dir).unwrap().count());
+    write_hi(tmp).persist_noclobber(&dest).unwrap();
+
+    let tmp = tempfile_fast::Builder::new()
+        .probe_link(false)
+        .tempfile_in(&temp_dir)
+        .unwrap();
+    tmp.persist_by_rename(&dest).unwrap();
+    assert_eq!(0, fs::metadata(&dest).unwrap().len());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn long_paths() {
+    use std::ffi::CString;
+    use std::os::fd::AsFd;
+    use std::os::fd::AsRawFd;
+    use std::os::fd::FromRawFd;
+    use std::os::fd::OwnedFd;
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-long")
+        .tempdir()
+        .unwrap();
+
+    // too deep to name in one go, so made, and opened, one directory at a time
+    let long = "d".repeat(250);
+    let name = CString::new(long.as_str()).unwrap();
+    let mut dir = OwnedFd::from(fs::File::open(temp_dir.path()).unwrap());
+    let mut path = temp_dir.path().to_path_buf();
+    for _ in 0..20 {
+        assert_eq!(0, unsafe {
+            libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o700)
+        });
+        let fd = unsafe {
+            libc::openat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                libc::O_DIRECTORY | libc::O_RDONLY | libc::O_CLOEXEC,
+            )
+        };
+        assert!(fd >= 0);
+        dir = unsafe { OwnedFd::from_raw_fd(fd) };
+        path.push(&long);
+    }
+    assert!(path.as_os_str().len() > libc::PATH_MAX as usize);
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let e = tmp.persist_noclobber(path.join("dest")).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidFilename, e.error.kind());
+    assert!(e.error.to_string().contains("PATH_MAX"), "{}", e.error);
+
+    // relative to the directory, the length doesn't matter
+    let tmp = tempfile_fast::Builder::new()
+        .tempfile_in_dir_fd(dir.as_fd())
+        .unwrap();
+    write_hi(tmp)
+        .persist_noclobber_at(dir.as_fd(), "dest")
+        .unwrap();
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let e = tmp
+        .persist_noclobber(temp_dir.path().join("n".repeat(300)))
+        .unwrap_err();
+    assert!(e.error.to_string().contains("NAME_MAX"), "{}", e.error);
+}
+
+#[test]
+fn fallback_across_filesystems() {
+    let shm = std::path::Path::new("/dev/shm");
+    if !shm.is_dir() {
+        return;
+    }
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-across")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+
+    let named = tempfile::NamedTempFile::new_in(shm).unwrap();
+    let e = write_hi(PersistableTempFile::Fallback(named))
+        .persist_by_rename(&dest)
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::CrossesDevices, e.error.kind());
+
+    let mut options = tempfile_fast::RenameOptions::new();
+    options.copy_across_filesystems(true);
+    e.file.persist_by_rename_with(&dest, &mut options).unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+
+    let named = tempfile::NamedTempFile::new_in(shm).unwrap();
+    let e = write_hi(PersistableTempFile::Fallback(named))
+        .persist_noclobber(&dest)
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::CrossesDevices, e.error.kind());
+}
+
+#[test]
+fn copy_across_filesystems() {
+    let shm = std::path::Path::new("/dev/shm");
+    if !shm.is_dir() {
+        return;
+    }
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-across")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+
+    let e = write_hi(PersistableTempFile::new_in(shm).unwrap())
+        .persist_by_rename(&dest)
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::CrossesDevices, e.error.kind());
+    let e = e.file.persist_noclobber(&dest).unwrap_err();
+    assert_eq!(std::io::ErrorKind::CrossesDevices, e.error.kind());
+
+    let mut options = tempfile_fast::RenameOptions::new();
+    options.copy_across_filesystems(true);
+    e.file.persist_by_rename_with(&dest, &mut options).unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+}
+
+#[test]
+fn new_in_exclusive() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-exclusive")
+        .tempdir()
+        .unwrap();
+
+    match PersistableTempFile::new_in_exclusive(&temp_dir) {
+        Ok(tmp) => {
+            assert!(matches!(tmp, PersistableTempFile::Linux(_)));
+            assert_eq!(0, fs::read_dir(&temp_dir).unwrap().count());
+        }
+        Err(e) => assert_eq!(std::io::ErrorKind::Unsupported, e.kind()),
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let missing = temp_dir.path().join("missing");
+        let e = PersistableTempFile::new_in_exclusive(missing).unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, e.kind());
+    }
+}
+
+#[test]
+fn discard() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let mut tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+    tmp.write_all(b"unwanted").unwrap();
+    tmp.discard().unwrap();
+
+    let named = tempfile::NamedTempFile::new_in(&temp_dir).unwrap();
+    PersistableTempFile::Fallback(named).discard().unwrap();
+
+    assert_eq!(0, fs::read_dir(&temp_dir).unwrap().count());
+}
+
+#[test]
+fn keep() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let named = tempfile::NamedTempFile::new_in(&temp_dir).unwrap();
+    let (mut file, path) = PersistableTempFile::Fallback(named).keep().unwrap();
+    file.write_all(b"kept").unwrap();
+    drop(file);
+    assert_eq!(b"kept", fs::read(path.unwrap()).unwrap().as_slice());
+
+    let tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+    let linux = matches!(tmp, PersistableTempFile::Linux(_));
+    let (_file, path) = tmp.keep().unwrap();
+    assert_eq!(linux, path.is_none());
+}
+
+#[test]
+fn new_in_with_builder() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("configured-");
+    let tmp = PersistableTempFile::new_in_with_builder(&temp_dir, &builder).unwrap();
+    if let PersistableTempFile::Fallback(ref named) = tmp {
+        let name = named.path().file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("configured-"), "{}", name);
+    }
+
+    let dest = temp_dir.path().join("dest");
+    write_hi(tmp).persist_noclobber(&dest).unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn can_link_to_dir() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    assert!(tmp.can_link_to_dir(&temp_dir).unwrap());
+    assert!(!tmp.can_link_to_dir("/proc").unwrap());
+
+    let memfd = PersistableTempFile::new_memfd("can_link_to_dir").unwrap();
+    assert!(!memfd.can_link_to_dir(&temp_dir).unwrap());
+
+    // asking didn't use up the file
+    let dest = temp_dir.path().join("dest");
+    tmp.persist_noclobber(&dest).unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+}
+
+#[test]
+fn swap() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let a = temp_dir.path().join("a");
+    let b = temp_dir.path().join("b");
+    fs::write(&a, b"active").unwrap();
+    fs::write(&b, b"standby").unwrap();
+
+    match PersistableTempFile::swap(&a, &b) {
+        Ok(()) => {
+            assert_eq!(b"standby", fs::read(&a).unwrap().as_slice());
+            assert_eq!(b"active", fs::read(&b).unwrap().as_slice());
+        }
+        Err(e) => {
+            assert_eq!(std::io::ErrorKind::Unsupported, e.kind());
+            assert_eq!(b"active", fs::read(&a).unwrap().as_slice());
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn temp_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+
+    let tmp = tempfile_fast::Builder::new()
+        .temp_mode(0o640)
+        .tempfile_in(&temp_dir)
+        .unwrap();
+    // assuming a umask which doesn't mask group read
+    assert_eq!(0o640, tmp.metadata().unwrap().permissions().mode() & 0o777);
+
+    let tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+    assert_eq!(0o600, tmp.metadata().unwrap().permissions().mode() & 0o777);
+}
+
+#[cfg(unix)]
+#[test]
+fn io_block_size() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+    let size = tmp.io_block_size().unwrap();
+    assert!(size >= 512, "{}", size);
+}
+
+#[test]
+fn punch_hole() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let mut tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+    tmp.write_all(&[7u8; 256 * 1024]).unwrap();
+    tmp.sync_all().unwrap();
+
+    match tmp.punch_hole(64 * 1024, 128 * 1024) {
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return,
+        other => other.unwrap(),
+    }
+    assert_eq!(256 * 1024, tmp.stream_position().unwrap());
+
+    let dest = temp_dir.path().join("dest");
+    tmp.persist_noclobber(&dest).unwrap();
+    let found = fs::read(&dest).unwrap();
+    assert_eq!(256 * 1024, found.len());
+    assert!(found[..64 * 1024].iter().all(|&b| b == 7));
+    assert!(found[64 * 1024..192 * 1024].iter().all(|&b| b == 0));
+    assert!(found[192 * 1024..].iter().all(|&b| b == 7));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert!(fs::metadata(&dest).unwrap().blocks() * 512 < 256 * 1024);
+    }
+}
+
+#[test]
+fn with_name() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let mut tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+    tmp.write_all(b"hello").unwrap();
+
+    let found = tmp
+        .with_name(&temp_dir, |path| fs::read(path).unwrap())
+        .unwrap();
+    assert_eq!(b"hello", found.as_slice());
+    assert_eq!(1, fs::read_dir(&temp_dir).unwrap().count());
+
+    let dest = temp_dir.path().join("dest");
+    tmp.persist_noclobber(&dest).unwrap();
+    assert_eq!(b"hello", fs::read(&dest).unwrap().as_slice());
+    assert_eq!(1, fs::read_dir(&temp_dir).unwrap().count());
+}
+
+#[cfg(unix)]
+#[test]
+fn dir_fd() {
+    use std::os::fd::AsFd;
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let before = temp_dir.path().join("before");
+    let after = temp_dir.path().join("after");
+    fs::create_dir(&before).unwrap();
+    let dir = fs::File::open(&before).unwrap();
+
+    let mut tmp = PersistableTempFile::new_in_dir_fd(dir.as_fd()).unwrap();
+    tmp.write_all(b"hello").unwrap();
+
+    fs::rename(&before, &after).unwrap();
+    tmp.persist_noclobber_at(dir.as_fd(), "dest").unwrap();
+    assert_eq!(b"hello", fs::read(after.join("dest")).unwrap().as_slice());
+}
+
+#[cfg(unix)]
+#[test]
+fn persist_by_rename_at() {
+    use std::os::fd::AsFd;
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+    fs::write(&dest, b"old").unwrap();
+    let dir = fs::File::open(&temp_dir).unwrap();
+
+    let mut tmp = PersistableTempFile::new_in_dir_fd(dir.as_fd()).unwrap();
+    tmp.write_all(b"new").unwrap();
+    tmp.persist_by_rename_at(dir.as_fd(), "dest").unwrap();
+    assert_eq!(b"new", fs::read(&dest).unwrap().as_slice());
+    assert_eq!(1, fs::read_dir(&temp_dir).unwrap().count());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn detached_mount() {
+    use std::ffi::CString;
+    use std::os::fd::AsFd;
+    use std::os::fd::FromRawFd;
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+    fs::write(&dest, b"old").unwrap();
+
+    let path = CString::new(temp_dir.path().as_os_str().as_bytes()).unwrap();
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            // OPEN_TREE_CLONE
+            1 | libc::O_CLOEXEC,
+        )
+    };
+    if -1 == fd {
+        // needs CAP_SYS_ADMIN, and Linux 5.2
+        return;
+    }
+    let mount = unsafe { fs::File::from_raw_fd(fd as i32) };
+
+    let mut tmp = match PersistableTempFile::new_in_dir_fd(mount.as_fd()) {
+        Ok(tmp) => tmp,
+        Err(e) => {
+            // the detached mount has no path to fall back to
+            assert_eq!(std::io::ErrorKind::Unsupported, e.kind(), "{e}");
+            return;
+        }
+    };
+    tmp.write_all(b"new").unwrap();
+    tmp.persist_by_rename_at(mount.as_fd(), "dest").unwrap();
+    assert_eq!(b"new", fs::read(&dest).unwrap().as_slice());
+    assert_eq!(1, fs::read_dir(&temp_dir).unwrap().count());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn from_raw_fd_linux() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let path = CString::new(temp_dir.path().as_os_str().as_bytes()).unwrap();
+    let fd = unsafe {
+        libc::open(
+            path.as_ptr(),
+            libc::O_TMPFILE | libc::O_RDWR | libc::O_CLOEXEC,
+            0o600,
+        )
+    };
+    if -1 == fd {
+        // no O_TMPFILE support here
+        return;
+    }
+
+    let mut tmp = unsafe { PersistableTempFile::from_raw_fd_linux(fd) };
+    tmp.write_all(b"adopted").unwrap();
+    let dest = temp_dir.path().join("dest");
+    tmp.persist_noclobber(&dest).unwrap();
+    assert_eq!(b"adopted", fs::read(&dest).unwrap().as_slice());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn memfd() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+
+    let mut tmp = PersistableTempFile::new_memfd("memfd-test").unwrap();
+    tmp.write_all(b"from memory").unwrap();
+    let dest = temp_dir.path().join("dest");
+    fs::write(&dest, b"old").unwrap();
+    tmp.persist_by_rename(&dest).unwrap();
+    assert_eq!(b"from memory", fs::read(&dest).unwrap().as_slice());
+
+    let mut tmp = PersistableTempFile::new_memfd("memfd-test").unwrap();
+    tmp.write_all(b"again").unwrap();
+    let tmp = match tmp.persist_noclobber(&dest) {
+        Ok(()) => panic!("overwrote an existing file"),
+        Err(e) => {
+            assert_eq!(std::io::ErrorKind::AlreadyExists, e.error.kind());
+            e.file
+        }
+    };
+    let other = temp_dir.path().join("other");
+    tmp.persist_noclobber(&other).unwrap();
+    assert_eq!(b"again", fs::read(&other).unwrap().as_slice());
+
+    // only the two destinations; no temporary files left behind
+    assert_eq!(2, fs::read_dir(&temp_dir).unwrap().count());
+}
+
+#[test]
+fn persist_by_rename_with() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+    fs::write(&dest, b"old").unwrap();
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let report = tmp
+        .persist_by_rename_with(&dest, &mut RenameOptions::new())
+        .unwrap();
+    assert_eq!(0, report.retries);
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+}
+
+#[test]
+fn rename_name() {
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+    fs::write(&dest, b"old").unwrap();
+    fs::write(temp_dir.path().join("#taken"), b"").unwrap();
+
+    let names = Arc::new(Mutex::new(Vec::new()));
+    let mut options = RenameOptions::new();
+    {
+        let names = names.clone();
+        options.name(move |random| {
+            let name = if names.lock().unwrap().is_empty() {
+                "#taken".to_string()
+            } else {
+                format!("#{:x}", random)
+            };
+            names.lock().unwrap().push(name.clone());
+            name.into()
+        });
+    }
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let report = tmp.persist_by_rename_with(&dest, &mut options).unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+    assert_eq!(2, fs::read_dir(&temp_dir).unwrap().count());
+
+    let names = names.lock().unwrap();
+    if let [ref taken, ref chosen] = names[..] {
+        assert_eq!("#taken", taken);
+        assert!(chosen.starts_with('#'));
+        assert_eq!(1, report.retries);
+    } else {
+        // the fast path is unavailable, so no intermediate name was needed
+        assert_eq!(0, report.retries);
+    }
+}
+
+#[test]
+fn rename_name_must_be_plain() {
+    fn is_send<T: Send>(_: &T) {}
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+    fs::write(&dest, b"old").unwrap();
+
+    for bad in ["../escape", "/escape", "sub/escape", ".", ""] {
+        let mut options = RenameOptions::new();
+        options.name(move |_| bad.into());
+        is_send(&options);
+
+        let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+        if let PersistableTempFile::Fallback(_) = tmp {
+            // tempfile picks the Fallback's names
+            return;
+        }
+        let e = tmp.persist_by_rename_with(&dest, &mut options).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, e.error.kind(), "{bad}");
+    }
+    assert_eq!(b"old", fs::read(&dest).unwrap().as_slice());
+    assert_eq!(1, fs::read_dir(&temp_dir).unwrap().count());
+}
+
+#[test]
+fn staging_dir() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let watched = temp_dir.path().join("watched");
+    let staging = temp_dir.path().join(".tmp");
+    fs::create_dir(&watched).unwrap();
+    fs::create_dir(&staging).unwrap();
+    let dest = watched.join("dest");
+    fs::write(&dest, b"old").unwrap();
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    tmp.persist_by_rename_with(&dest, RenameOptions::new().staging_dir(&staging))
+        .unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+    assert_eq!(1, fs::read_dir(&watched).unwrap().count());
+    assert_eq!(0, fs::read_dir(&staging).unwrap().count());
+
+    // on another filesystem, so the destination's own directory is used instead
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    tmp.persist_by_rename_with(&dest, RenameOptions::new().staging_dir("/dev"))
+        .unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn link_fd() {
+    use std::os::fd::AsFd;
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let file = match write_hi(PersistableTempFile::new_in(&temp_dir).unwrap()) {
+        PersistableTempFile::Linux(file) => file,
+        PersistableTempFile::Fallback(_) => return,
+    };
+
+    let dest = temp_dir.path().join("linked");
+    tempfile_fast::link_fd(file.as_fd(), &dest).unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+
+    let e = tempfile_fast::link_fd(file.as_fd(), &dest).unwrap_err();
+    assert_eq!(std::io::ErrorKind::AlreadyExists, e.kind());
+}
+
+#[test]
+fn real_errors_not_hidden() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let e = PersistableTempFile::new_in(temp_dir.path().join("missing")).unwrap_err();
+    assert_eq!(std::io::ErrorKind::NotFound, e.kind());
+}
+
+#[test]
+fn persist_by_rename_path() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let dest = tmp.persist_by_rename_path(dest).unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+
+    let missing = temp_dir.path().join("missing").join("dest");
+    let tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+    let mut e = tmp.persist_by_rename_path(missing.clone()).unwrap_err();
+    assert_eq!(Some(missing.as_path()), e.dest());
+    assert_eq!(Some(missing), e.take_dest());
+    assert_eq!(None, e.dest());
+}
+
+#[test]
+fn persist_noclobber_open() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-open")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let mut opened = tmp.persist_noclobber_open(&dest).unwrap();
+    let mut content = String::new();
+    opened.read_to_string(&mut content).unwrap();
+    assert_eq!("hi", content);
+    #[cfg(target_os = "linux")]
+    assert!(opened.write_all(b"no").is_err());
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let e = tmp.persist_noclobber_open(&dest).unwrap_err();
+    assert_eq!(std::io::ErrorKind::AlreadyExists, e.error.kind());
+}
+
+#[test]
+fn persist_durable() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-durable")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    tmp.persist_noclobber_durable(&dest).unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let e = tmp.persist_noclobber_durable(&dest).unwrap_err();
+    assert_eq!(std::io::ErrorKind::AlreadyExists, e.error.kind());
+    assert_ne!(PersistStage::SyncDir, e.stage);
+    let other = temp_dir.path().join("other");
+    e.file.persist_noclobber_durable(&other).unwrap();
+    assert_eq!(b"hi", fs::read(&other).unwrap().as_slice());
+
+    let mut tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+    tmp.write_all(b"replaced").unwrap();
+    tmp.persist_by_rename_durable(&dest).unwrap();
+    assert_eq!(b"replaced", fs::read(&dest).unwrap().as_slice());
+}
+
+#[test]
+fn stream_position() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-position")
+        .tempdir()
+        .unwrap();
+    let mut tmp = PersistableTempFile::new_in(&temp_dir).unwrap();
+    assert_eq!(0, tmp.stream_position().unwrap());
+    tmp.write_all(b"hello").unwrap();
+    assert_eq!(5, tmp.stream_position().unwrap());
+    tmp.seek(SeekFrom::Start(1)).unwrap();
+    assert_eq!(1, tmp.stream_position().unwrap());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn persist_by_rename_retry() {
+    use std::process::Command;
+    use std::thread;
+    use std::time::Duration;
+
+    fn chattr(flag: &str, path: &std::path::Path) -> bool {
+        Command::new("chattr")
+            .arg(flag)
+            .arg(path)
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let dest = temp_dir.path().join("dest");
+    fs::write(&dest, b"old").unwrap();
+
+    // an immutable file can't be renamed over, even by root; standing in for a busy share
+    if !chattr("+i", &dest) {
+        return;
+    }
+
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let e = tmp
+        .persist_by_rename_retry(&dest, 1, Duration::from_millis(10))
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::PermissionDenied, e.error.kind());
+
+    let release = {
+        let dest = dest.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            assert!(chattr("-i", &dest));
+        })
+    };
+    // a fresh file: the kernel won't link the first one again, now it has had a name
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let retried = tmp.persist_by_rename_retry(&dest, 10, Duration::from_millis(10));
+    release.join().unwrap();
+    retried.unwrap();
+
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+}
+
+#[test]
+fn to_report() {
+    let temp_dir = tempfile::Builder::default()
+        .prefix("tempfile-deleted")
+        .tempdir()
+        .unwrap();
+    let missing = temp_dir.path().join("missing").join("dest");
+    let tmp = write_hi(PersistableTempFile::new_in(&temp_dir).unwrap());
+    let e = tmp.persist_by_rename_path(missing.clone()).unwrap_err();
+
+    let report = e.to_report();
+    assert_eq!(report, report.clone());
+    assert_eq!(e.error.kind(), report.kind);
+    assert_eq!(e.error.to_string(), report.message);
+    assert_eq!(e.stage, report.stage);
+    assert_eq!(Some(missing), report.dest);
+
+    // the file is still there to recover
+    let dest = temp_dir.path().join("dest");
+    e.file.persist_noclobber(&dest).unwrap();
+    assert_eq!(b"hi", fs::read(&dest).unwrap().as_slice());
+}
@@ -0,0 +1,69 @@
+//! Tests which change the current directory, so are kept out of the other tests' process, and
+//! take turns.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+static CURRENT_DIR: Mutex<()> = Mutex::new(());
+
+/// Run `f` with the current directory changed to `dir`.
+fn in_dir<T>(dir: &Path, f: impl FnOnce() -> T) -> T {
+    let _guard = CURRENT_DIR.lock().unwrap_or_else(|e| e.into_inner());
+    env::set_current_dir(dir).unwrap();
+    f()
+}
+
+#[test]
+fn new_for_relative() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let first = dir.path().join("first");
+    let second = dir.path().join("second");
+    fs::create_dir(&first)?;
+    fs::create_dir(&second)?;
+
+    let (relative, absolute) = in_dir(&first, || -> Result<_, io::Error> {
+        Ok((
+            tempfile_fast::Sponge::new_for_relative("relative.txt")?,
+            tempfile_fast::Sponge::new_for("absolute.txt")?,
+        ))
+    })?;
+
+    // the relative name is resolved again at commit time, the absolute one isn't
+    in_dir(&second, || -> Result<(), io::Error> {
+        for mut sponge in [relative, absolute] {
+            sponge.write_all(b"written")?;
+            sponge.commit_durable()?;
+        }
+        Ok(())
+    })?;
+
+    assert_eq!("written", fs::read_to_string(second.join("relative.txt"))?);
+    assert_eq!("written", fs::read_to_string(first.join("absolute.txt"))?);
+    assert!(!first.join("relative.txt").exists());
+    assert!(!second.join("absolute.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+fn bare_names() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+
+    in_dir(dir.path(), || -> Result<(), io::Error> {
+        for sponge in [
+            tempfile_fast::Sponge::new_for("bare.txt")?,
+            tempfile_fast::Sponge::new_for_relative("bare.txt")?,
+        ] {
+            let mut sponge = sponge;
+            sponge.write_all(b"bare")?;
+            sponge.commit()?;
+            assert_eq!("bare", fs::read_to_string(dir.path().join("bare.txt"))?);
+            fs::remove_file(dir.path().join("bare.txt"))?;
+        }
+        Ok(())
+    })
+}
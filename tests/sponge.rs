@@ -2,6 +2,7 @@ use std::fs;
 use std::io;
 use std::io::Read;
 use std::io::Write;
+use std::path::Path;
 
 #[test]
 fn sponge() -> Result<(), io::Error> {
@@ -30,8 +31,1848 @@ fn sponge() -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Set or clear a file attribute, e.g. `+i`, returning whether it worked: it needs root, and a
+/// filesystem which supports it.
+#[cfg(target_os = "linux")]
+fn chattr(flag: &str, path: &Path) -> bool {
+    std::process::Command::new("chattr")
+        .arg(flag)
+        .arg(path)
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Run `f` on a new thread with the credentials of `nobody`, in the supplementary `groups`, or
+/// return `None` if we aren't root, so can't. The raw syscalls change only that thread; the libc
+/// wrappers would change them all.
+#[cfg(target_os = "linux")]
+fn as_nobody<T: Send>(groups: &[libc::gid_t], f: impl FnOnce() -> T + Send) -> Option<T> {
+    const NOBODY: libc::c_long = 65534;
+
+    if 0 != unsafe { libc::geteuid() } {
+        return None;
+    }
+
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| unsafe {
+                assert_eq!(
+                    0,
+                    libc::syscall(libc::SYS_setgroups, groups.len(), groups.as_ptr())
+                );
+                assert_eq!(
+                    0,
+                    libc::syscall(libc::SYS_setresgid, NOBODY, NOBODY, NOBODY)
+                );
+                assert_eq!(
+                    0,
+                    libc::syscall(libc::SYS_setresuid, NOBODY, NOBODY, NOBODY)
+                );
+                Some(f())
+            })
+            .join()
+            .unwrap()
+    })
+}
+
+/// Run `f` on a new thread which, though root, can't change the mode of files it doesn't own
+/// (`CAP_FOWNER`), or return `None` if we aren't root. Capabilities are per-thread.
+#[cfg(target_os = "linux")]
+fn without_fowner<T: Send>(f: impl FnOnce() -> T + Send) -> Option<T> {
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+    const CAP_FOWNER: u32 = 3;
+
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid: libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct Data {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    if 0 != unsafe { libc::geteuid() } {
+        return None;
+    }
+
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| unsafe {
+                let mut header = Header {
+                    version: LINUX_CAPABILITY_VERSION_3,
+                    pid: 0,
+                };
+                let mut data = [Data {
+                    effective: 0,
+                    permitted: 0,
+                    inheritable: 0,
+                }; 2];
+                assert_eq!(
+                    0,
+                    libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr())
+                );
+                data[0].effective &= !(1 << CAP_FOWNER);
+                assert_eq!(0, libc::syscall(libc::SYS_capset, &header, data.as_ptr()));
+                Some(f())
+            })
+            .join()
+            .unwrap()
+    })
+}
+
 fn read<R: Read>(mut thing: R) -> String {
     let mut s = String::new();
     thing.read_to_string(&mut s).unwrap();
     s
 }
+
+#[cfg(unix)]
+#[test]
+fn non_regular() -> Result<(), io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .allow_non_regular(true)
+        .new_for("/dev/null")?;
+    sponge.write_all(b"discarded")?;
+    sponge.commit()?;
+
+    assert!(fs::metadata("/dev/null")?.file_type().is_char_device());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn lock_metadata() -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("locked.txt");
+    fs::File::create(&test_path)?;
+    fs::set_permissions(&test_path, fs::Permissions::from_mode(0o640))?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.lock_metadata()?;
+
+    fs::set_permissions(&test_path, fs::Permissions::from_mode(0o604))?;
+
+    sponge.write_all(b"locked")?;
+    sponge.commit()?;
+
+    assert_eq!(
+        0o640,
+        fs::metadata(&test_path)?.permissions().mode() & 0o777
+    );
+    assert_eq!("locked", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn commit_durable() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("durable.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"on disk")?;
+    sponge.commit_durable()?;
+
+    assert_eq!("on disk", read(fs::File::open(&test_path)?));
+    assert_eq!(1, fs::read_dir(dir.path())?.count());
+
+    Ok(())
+}
+
+#[test]
+fn read_back() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("read.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"hello")?;
+
+    let mut buf = [0u8; 16];
+    let found = sponge.read(&mut buf)?;
+    assert_eq!(b"hello", &buf[..found]);
+    assert_eq!(0, sponge.read(&mut buf)?);
+
+    sponge.write_all(b" world")?;
+    let found = sponge.read(&mut buf)?;
+    assert_eq!(b" world", &buf[..found]);
+
+    sponge.commit()?;
+    assert_eq!("hello world", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn max_size() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("limited.txt");
+
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .max_size(8)
+        .new_for(&test_path)?;
+    sponge.write_all(b"12345")?;
+    let err = sponge.write_all(b"6789").unwrap_err();
+    assert_eq!(io::ErrorKind::FileTooLarge, err.kind());
+    sponge.write_all(b"678")?;
+
+    sponge.commit()?;
+    assert_eq!("12345678", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn commit_durable_in() -> Result<(), io::Error> {
+    use std::os::fd::AsFd;
+
+    let dir = tempfile::TempDir::new()?;
+    let handle = fs::File::open(dir.path())?;
+
+    for name in &["a.txt", "b.txt"] {
+        let mut sponge = tempfile_fast::Sponge::new_for(dir.path().join(name))?;
+        sponge.write_all(name.as_bytes())?;
+        sponge.commit_durable_in(Some(handle.as_fd()))?;
+    }
+    handle.sync_all()?;
+
+    assert_eq!("b.txt", read(fs::File::open(dir.path().join("b.txt"))?));
+
+    let elsewhere = tempfile::TempDir::new()?;
+    let wrong = fs::File::open(elsewhere.path())?;
+    let sponge = tempfile_fast::Sponge::new_for(dir.path().join("c.txt"))?;
+    let err = sponge.commit_durable_in(Some(wrong.as_fd())).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    assert!(!dir.path().join("c.txt").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn new_for_like() -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let template = dir.path().join("template.log");
+    fs::File::create(&template)?;
+    fs::set_permissions(&template, fs::Permissions::from_mode(0o604))?;
+
+    let test_path = dir.path().join("new.log");
+    let mut sponge = tempfile_fast::Sponge::new_for_like(&test_path, &template)?;
+    sponge.write_all(b"like")?;
+    sponge.commit()?;
+
+    assert_eq!(
+        0o604,
+        fs::metadata(&test_path)?.permissions().mode() & 0o777
+    );
+
+    match tempfile_fast::Sponge::new_for_like(&test_path, dir.path().join("missing")) {
+        Ok(_) => panic!("template doesn't exist"),
+        Err(e) => assert_eq!(io::ErrorKind::NotFound, e.kind()),
+    }
+
+    // with the other options, from the builder
+    let then = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    fs::File::options()
+        .write(true)
+        .open(&template)?
+        .set_modified(then)?;
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .preserve_times(true)
+        .new_for_like(&test_path, &template)?;
+    sponge.write_all(b"like, then")?;
+    sponge.commit()?;
+    assert_eq!(then, fs::metadata(&test_path)?.modified()?);
+
+    Ok(())
+}
+
+#[test]
+fn commit_after_barrier() -> Result<(), io::Error> {
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let dir = tempfile::TempDir::new()?;
+    let log_path = dir.path().join("log");
+    let data_path = dir.path().join("data");
+    let barrier = Arc::new(tempfile_fast::Barrier::new());
+
+    let mut log = tempfile_fast::Sponge::new_for(&log_path)?;
+    log.write_all(b"intent")?;
+
+    let mut data = tempfile_fast::Sponge::new_for(&data_path)?;
+    data.write_all(b"state")?;
+
+    let (committed, recv) = mpsc::channel();
+    let waiting = {
+        let barrier = barrier.clone();
+        let log_path = log_path.clone();
+        thread::spawn(move || {
+            let result = data.commit_after(&barrier);
+            // whatever the timing, the log must be in place before the data's commit returns
+            let log = fs::File::open(&log_path).map(read);
+            committed.send(()).unwrap();
+            (result, log)
+        })
+    };
+
+    assert_eq!(
+        Err(mpsc::RecvTimeoutError::Timeout),
+        recv.recv_timeout(Duration::from_millis(50)),
+        "committed before the barrier was released"
+    );
+    assert!(!data_path.exists());
+
+    log.commit_before(&barrier)?;
+    recv.recv().unwrap();
+    let (result, log) = waiting.join().unwrap();
+    result?;
+    assert_eq!("intent", log?);
+    assert_eq!("state", read(fs::File::open(&data_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn commit_after_failed_barrier() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let data_path = dir.path().join("data");
+    let barrier = tempfile_fast::Barrier::new();
+
+    let log = tempfile_fast::Sponge::new_for(dir.path().join("log"))?;
+    let mut data = tempfile_fast::Sponge::new_for(&data_path)?;
+    data.write_all(b"state")?;
+
+    // the log's directory is gone, so its commit fails
+    dir.close()?;
+    assert!(log.commit_before(&barrier).is_err());
+    assert!(data.commit_after(&barrier).is_err());
+    assert!(!data_path.exists());
+
+    Ok(())
+}
+
+// some filesystems, like macOS' APFS, refuse names which aren't UTF-8
+#[cfg(target_os = "linux")]
+#[test]
+fn non_utf8_paths() -> Result<(), io::Error> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let parent = dir.path().join(OsStr::from_bytes(b"dir-\xFF\xFE"));
+    let test_path = parent.join(OsStr::from_bytes(b"\xFF.txt"));
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"created")?;
+    sponge.commit()?;
+    assert_eq!("created", read(fs::File::open(&test_path)?));
+
+    // overwriting goes via a temporary name in the (non-UTF-8) parent
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"replaced")?;
+    sponge.commit()?;
+    assert_eq!("replaced", read(fs::File::open(&test_path)?));
+
+    assert_eq!(1, fs::read_dir(&parent)?.count());
+
+    Ok(())
+}
+
+#[test]
+fn checkpoint() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("checkpoint.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"prepared")?;
+    sponge.checkpoint()?;
+    assert!(!test_path.exists());
+
+    sponge.write_all(b", then committed")?;
+    sponge.commit()?;
+    assert_eq!(
+        "prepared, then committed",
+        read(fs::File::open(&test_path)?)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn strict_device() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("strict.txt");
+
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .strict_device(true)
+        .new_for(&test_path)?;
+    sponge.write_all(b"same device")?;
+    sponge.commit()?;
+    assert_eq!("same device", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[cfg(windows)]
+#[test]
+fn windows_hidden_attribute() -> Result<(), io::Error> {
+    use std::os::windows::fs::MetadataExt;
+    use std::process::Command;
+
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("hidden.ini");
+    fs::File::create(&test_path)?.write_all(b"old")?;
+    assert!(Command::new("attrib")
+        .arg("+h")
+        .arg(&test_path)
+        .status()?
+        .success());
+    let modified = fs::metadata(&test_path)?.modified()?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"new")?;
+    sponge.commit()?;
+
+    let metadata = fs::metadata(&test_path)?;
+    assert_ne!(0, metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN);
+    assert_eq!(modified, metadata.modified()?);
+    assert_eq!("new", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn directory_destination() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+
+    for path in &[dir.path().to_path_buf(), dir.path().join("new-dir/")] {
+        match tempfile_fast::Sponge::new_for(path) {
+            Ok(_) => panic!("accepted directory {:?}", path),
+            Err(e) => assert_eq!(io::ErrorKind::IsADirectory, e.kind()),
+        }
+    }
+
+    assert_eq!(0, fs::read_dir(dir.path())?.count());
+
+    Ok(())
+}
+
+#[test]
+fn try_commit_noclobber() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("noclobber.txt");
+    fs::File::create(&test_path)?.write_all(b"someone else's")?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"mine")?;
+
+    let sponge = match sponge.try_commit_noclobber() {
+        Ok(()) => panic!("overwrote an existing file"),
+        Err((e, sponge)) => {
+            assert_eq!(io::ErrorKind::AlreadyExists, e.kind());
+            sponge
+        }
+    };
+    assert_eq!("someone else's", read(fs::File::open(&test_path)?));
+
+    fs::remove_file(&test_path)?;
+    if let Err((e, _)) = sponge.try_commit_noclobber() {
+        return Err(e);
+    }
+    assert_eq!("mine", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn commit_noclobber_unowned() -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new()?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777))?;
+    let test_path = dir.path().join("root.conf");
+    // owned by root, so nobody can't give the new file its ownership
+    fs::write(&test_path, b"root's")?;
+
+    let written = as_nobody(&[], || -> Result<bool, io::Error> {
+        let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+        sponge.write_all(b"nobody's")?;
+        sponge.commit_noclobber()
+    });
+
+    if let Some(written) = written {
+        assert!(!written?);
+    }
+    assert_eq!("root's", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn apply_umask() -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let status = fs::read_to_string("/proc/self/status")?;
+    let umask = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Umask:"))
+        .map(|mask| u32::from_str_radix(mask.trim(), 8).unwrap())
+        .unwrap();
+
+    let dir = tempfile::TempDir::new()?;
+    let masked = dir.path().join("masked.txt");
+    let private = dir.path().join("private.txt");
+
+    tempfile_fast::Sponge::builder()
+        .apply_umask(true)
+        .new_for(&masked)?
+        .commit()?;
+    tempfile_fast::Sponge::new_for(&private)?.commit()?;
+
+    let mode =
+        |path| -> Result<u32, io::Error> { Ok(fs::metadata(path)?.permissions().mode() & 0o777) };
+    assert_eq!(0o666 & !umask, mode(&masked)?);
+    assert_eq!(0o600, mode(&private)?);
+
+    fs::set_permissions(&masked, fs::Permissions::from_mode(0o640))?;
+    tempfile_fast::Sponge::builder()
+        .apply_umask(true)
+        .new_for(&masked)?
+        .commit()?;
+    assert_eq!(0o640, mode(&masked)?);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_symlinks() -> Result<(), io::Error> {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::TempDir::new()?;
+    let target = dir.path().join("target.txt");
+    let middle = dir.path().join("middle");
+    let link = dir.path().join("link");
+    fs::write(&target, b"old")?;
+    symlink("target.txt", &middle)?;
+    symlink(&middle, &link)?;
+
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .follow_symlinks(true)
+        .new_for(&link)?;
+    sponge.write_all(b"new")?;
+    sponge.commit()?;
+
+    assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+    assert!(fs::symlink_metadata(&middle)?.file_type().is_symlink());
+    assert_eq!("new", read(fs::File::open(&target)?));
+
+    let looped = dir.path().join("loop");
+    symlink("loop", &looped)?;
+    let e = tempfile_fast::Sponge::builder()
+        .follow_symlinks(true)
+        .new_for(&looped)?
+        .commit()
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, e.kind());
+
+    Ok(())
+}
+
+#[test]
+fn writer() -> Result<(), io::Error> {
+    fn produce<W: Write + Read>(mut out: W) -> Result<String, io::Error> {
+        out.write_all(b"from a writer")?;
+        let mut found = String::new();
+        out.read_to_string(&mut found)?;
+        Ok(found)
+    }
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("writer.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    assert_eq!("from a writer", produce(sponge.writer())?);
+    sponge.write_all(b", then directly")?;
+    sponge.commit()?;
+
+    assert_eq!(
+        "from a writer, then directly",
+        read(fs::File::open(&test_path)?)
+    );
+
+    Ok(())
+}
+
+#[cfg(windows)]
+#[test]
+fn sharing_retries() -> Result<(), io::Error> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::thread;
+    use std::time::Duration;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("busy.txt");
+    fs::write(&test_path, b"old")?;
+
+    // no sharing at all, like an over-eager virus scanner
+    let busy = fs::OpenOptions::new()
+        .read(true)
+        .share_mode(0)
+        .open(&test_path)?;
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        drop(busy);
+    });
+
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .sharing_retries(8)
+        .new_for(&test_path)?;
+    sponge.write_all(b"new")?;
+    sponge.commit()?;
+    release.join().unwrap();
+
+    assert_eq!("new", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[cfg(windows)]
+#[test]
+fn sharing_violation_without_retries() -> Result<(), io::Error> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("busy.txt");
+    fs::write(&test_path, b"old")?;
+
+    let busy = fs::OpenOptions::new()
+        .read(true)
+        .share_mode(0)
+        .open(&test_path)?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"new")?;
+    let e = sponge.commit().unwrap_err();
+    drop(busy);
+
+    // the system's own error, not wrapped up as having been retried
+    assert!(e.raw_os_error().is_some(), "{e}");
+    assert_eq!("old", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[cfg(windows)]
+#[test]
+fn windows_missing_drive() {
+    let missing = (b'D'..=b'Z')
+        .map(|letter| format!("{}:\\", letter as char))
+        .find(|root| fs::metadata(root).is_err())
+        .expect("every drive letter is in use");
+
+    let e = tempfile_fast::Sponge::new_for(Path::new(&missing).join("dir").join("file.txt"))
+        .unwrap_err();
+    assert!(e.to_string().contains("is not available"), "{}", e);
+}
+
+#[cfg(windows)]
+#[test]
+fn windows_replace_keeps_streams() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("streams.txt");
+    fs::write(&test_path, b"old")?;
+    let mut stream = test_path.clone().into_os_string();
+    stream.push(":extra");
+    fs::write(&stream, b"kept")?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"new")?;
+    assert!(sponge.commit_with_stats()?.replaced);
+
+    assert_eq!("new", read(fs::File::open(&test_path)?));
+    assert_eq!("kept", read(fs::File::open(&stream)?));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn setgid_preserved() -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("setgid-tool");
+    fs::write(&test_path, b"old")?;
+    // group-executable, so `chown` clears the setgid bit
+    fs::set_permissions(&test_path, fs::Permissions::from_mode(0o2755))?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"new")?;
+    sponge.commit()?;
+
+    assert_eq!(
+        0o2755,
+        fs::metadata(&test_path)?.permissions().mode() & 0o7777
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn dir_mode() -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let existing = dir.path().join("existing");
+    fs::create_dir(&existing)?;
+    fs::set_permissions(&existing, fs::Permissions::from_mode(0o755))?;
+    let test_path = existing.join("a").join("b").join("secret.txt");
+
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .dir_mode(0o700)
+        .new_for(&test_path)?;
+    sponge.write_all(b"secret")?;
+    sponge.commit()?;
+
+    let mode = |path: &Path| fs::metadata(path).map(|m| m.permissions().mode() & 0o777);
+    assert_eq!(0o755, mode(&existing)?);
+    assert_eq!(0o700, mode(&existing.join("a"))?);
+    assert_eq!(0o700, mode(&existing.join("a").join("b"))?);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn best_effort_group() -> Result<(), io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("shared.txt");
+    fs::write(&test_path, b"old")?;
+    let before = fs::metadata(&test_path)?;
+
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .best_effort_group(true)
+        .new_for(&test_path)?;
+    sponge.write_all(b"new")?;
+    sponge.commit()?;
+
+    let after = fs::metadata(&test_path)?;
+    assert_eq!(before.uid(), after.uid());
+    assert_eq!(before.gid(), after.gid());
+    assert_eq!("new", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn best_effort_group_refused() -> Result<(), io::Error> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    const STAFF: libc::gid_t = 50;
+
+    let dir = tempfile::TempDir::new()?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777))?;
+    let ours = dir.path().join("ours.txt");
+    let theirs = dir.path().join("theirs.txt");
+    fs::write(&ours, b"old")?;
+    fs::write(&theirs, b"old")?;
+    // root's, but in a group which nobody will be in
+    std::os::unix::fs::chown(&ours, Some(0), Some(STAFF))?;
+
+    let commit = |path: &Path, best_effort: bool| -> Result<(), io::Error> {
+        let mut sponge = tempfile_fast::Sponge::builder()
+            .best_effort_group(best_effort)
+            .new_for(path)?;
+        sponge.write_all(b"new")?;
+        sponge.commit()
+    };
+
+    let results = as_nobody(&[STAFF], || {
+        (
+            commit(&ours, false),
+            commit(&ours, true),
+            commit(&theirs, true),
+        )
+    });
+    let (strict, group_only, neither) = match results {
+        Some(results) => results,
+        None => return Ok(()),
+    };
+
+    assert_eq!(io::ErrorKind::PermissionDenied, strict.unwrap_err().kind());
+
+    // the owner couldn't be kept, but the group could
+    group_only?;
+    let metadata = fs::metadata(&ours)?;
+    assert_eq!((65534, STAFF), (metadata.uid(), metadata.gid()));
+    assert_eq!("new", read(fs::File::open(&ours)?));
+
+    // neither could be kept, so the new file is entirely nobody's
+    neither?;
+    let metadata = fs::metadata(&theirs)?;
+    assert_eq!((65534, 65534), (metadata.uid(), metadata.gid()));
+    assert_eq!("new", read(fs::File::open(&theirs)?));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn new_for_as() -> Result<(), io::Error> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("owned.txt");
+    fs::write(&test_path, b"old")?;
+    fs::set_permissions(&test_path, fs::Permissions::from_mode(0o600))?;
+    let ours = fs::metadata(&test_path)?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for_as(&test_path, ours.uid(), ours.gid(), 0o640)?;
+    sponge.write_all(b"new")?;
+    sponge.commit()?;
+
+    let found = fs::metadata(&test_path)?;
+    assert_eq!(0o640, found.permissions().mode() & 0o777);
+    assert_eq!((ours.uid(), ours.gid()), (found.uid(), found.gid()));
+
+    // with the other options, from the builder
+    let mut sponge = tempfile_fast::Sponge::builder().max_size(2).new_for_as(
+        &test_path,
+        ours.uid(),
+        ours.gid(),
+        0o640,
+    )?;
+    assert_eq!(
+        io::ErrorKind::FileTooLarge,
+        sponge.write_all(b"new").unwrap_err().kind()
+    );
+
+    // only root can give files away
+    if 0 != ours.uid() {
+        match tempfile_fast::Sponge::new_for_as(&test_path, 0, 0, 0o640) {
+            Ok(_) => panic!("chown to root succeeded"),
+            Err(e) => assert_eq!(io::ErrorKind::PermissionDenied, e.kind()),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn bytes_written() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("counted.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    assert_eq!(0, sponge.bytes_written());
+    sponge.write_all(b"hello")?;
+    assert_eq!(5, sponge.bytes_written());
+
+    let bufs = [io::IoSlice::new(b", "), io::IoSlice::new(b"world")];
+    let written = sponge.write_vectored(&bufs)?;
+    assert_eq!(12, sponge.bytes_written());
+    assert_eq!(7, written);
+
+    sponge.commit()?;
+    assert_eq!("hello, world", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn try_commit_keeps_buffer() -> Result<(), io::Error> {
+    // writes to /dev/full always fail with ENOSPC
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .allow_non_regular(true)
+        .new_for("/dev/full")?;
+    sponge.write_all(b"lost?")?;
+
+    let sponge = match sponge.try_commit() {
+        Ok(()) => panic!("wrote to /dev/full"),
+        Err((e, sponge)) => {
+            assert_eq!(io::ErrorKind::StorageFull, e.kind());
+            assert!(e.to_string().contains("5 bytes unwritten"), "{}", e);
+            sponge
+        }
+    };
+
+    let e = sponge.commit().unwrap_err();
+    assert!(
+        e.to_string().contains("couldn't write buffered data"),
+        "{}",
+        e
+    );
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn flush_buffer() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("flushed.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"buffered")?;
+    sponge.flush()?;
+    sponge.flush_buffer()?;
+    assert!(!test_path.exists());
+    sponge.commit()?;
+    assert_eq!("buffered", read(fs::File::open(&test_path)?));
+
+    // written directly, so the flush is real
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .allow_non_regular(true)
+        .new_for("/dev/full")?;
+    sponge.write_all(b"lost")?;
+    assert_eq!(
+        io::ErrorKind::StorageFull,
+        sponge.flush().unwrap_err().kind()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn new_in_dir_named() -> Result<(), io::Error> {
+    use std::ffi::OsStr;
+
+    let dir = tempfile::TempDir::new()?;
+
+    let mut sponge = tempfile_fast::Sponge::new_in_dir_named(dir.path(), OsStr::new("named.txt"))?;
+    sponge.write_all(b"named")?;
+    sponge.commit()?;
+    assert_eq!("named", read(fs::File::open(dir.path().join("named.txt"))?));
+
+    for bad in ["", ".", "..", "a/b", "a/", "/a", "a\0b"] {
+        let e = tempfile_fast::Sponge::new_in_dir_named(dir.path(), OsStr::new(bad)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, e.kind(), "{bad:?}");
+    }
+
+    // with the other options, from the builder
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .max_size(2)
+        .new_in_dir_named(dir.path(), OsStr::new("limited.txt"))?;
+    assert_eq!(
+        io::ErrorKind::FileTooLarge,
+        sponge.write_all(b"new").unwrap_err().kind()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn try_into_named_temp() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("handed-over.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"handed over")?;
+
+    let named = match sponge.try_into_named_temp() {
+        Ok(named) => named,
+        Err(_) => panic!("the temporary file couldn't be named"),
+    };
+    assert_eq!(Some(dir.path()), named.path().parent());
+    assert_eq!("handed over", fs::read_to_string(named.path())?);
+    assert!(!test_path.exists());
+
+    let path = named.path().to_path_buf();
+    drop(named);
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn new_for_with_scratch() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let scratch = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("scratched.txt");
+    fs::write(&test_path, b"old")?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for_with_scratch(&test_path, scratch.path())?;
+    sponge.write_all(b"same filesystem")?;
+    let stats = sponge.commit_with_stats()?;
+    assert!(!stats.copy_fallback);
+    assert_eq!("same filesystem", read(fs::File::open(&test_path)?));
+
+    // usually a tmpfs, unlike the temp dir
+    let shm = Path::new("/dev/shm");
+    if !shm.is_dir() {
+        return Ok(());
+    }
+    let scratch = tempfile::TempDir::new_in(shm)?;
+    let mut sponge = tempfile_fast::Sponge::new_for_with_scratch(&test_path, scratch.path())?;
+    sponge.write_all(b"elsewhere")?;
+    let stats = sponge.commit_with_stats()?;
+    assert_eq!("elsewhere", read(fs::File::open(&test_path)?));
+    assert_eq!(0, fs::read_dir(scratch.path())?.count());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let elsewhere = fs::metadata(shm)?.dev() != fs::metadata(dir.path())?.dev();
+        assert_eq!(elsewhere, stats.copy_fallback);
+    }
+
+    Ok(())
+}
+
+/// An existing destination's directory isn't created, or even checked, so can be read-only,
+/// as long as the new file goes elsewhere.
+#[cfg(target_os = "linux")]
+#[test]
+fn existing_in_read_only_dir() -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new()?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755))?;
+    let read_only = dir.path().join("read-only");
+    fs::create_dir(&read_only)?;
+    let test_path = read_only.join("existing.txt");
+    fs::write(&test_path, b"old")?;
+    std::os::unix::fs::chown(&test_path, Some(65534), Some(65534))?;
+    fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555))?;
+    let scratch = dir.path().join("scratch");
+    fs::create_dir(&scratch)?;
+    std::os::unix::fs::chown(&scratch, Some(65534), Some(65534))?;
+
+    let written = as_nobody(&[], || -> Result<(), io::Error> {
+        let mut sponge = tempfile_fast::Sponge::new_for_with_scratch(&test_path, &scratch)?;
+        sponge.write_all(b"new")?;
+        sponge.commit_in_place_non_atomic()
+    });
+
+    if let Some(written) = written {
+        written?;
+        assert_eq!("new", read(fs::File::open(&test_path)?));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tee() -> Result<(), io::Error> {
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("tee.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    let mut tee = sponge.tee(Vec::new());
+    tee.write_all(b"hello ")?;
+    tee.write_all(b"world")?;
+    assert_eq!(b"hello world", tee.into_other()?.as_slice());
+
+    let mut tee = sponge.tee(Broken);
+    tee.write_all(b"!")?;
+    assert_eq!(
+        io::ErrorKind::BrokenPipe,
+        tee.into_other().err().unwrap().kind()
+    );
+
+    sponge.commit()?;
+    assert_eq!("hello world!", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn stream_position() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("position.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    assert_eq!(0, sponge.stream_position()?);
+    sponge.write_all(b"hello")?;
+    assert_eq!(5, sponge.stream_position()?);
+    sponge.flush_buffer()?;
+    sponge.write_all(b" world")?;
+    assert_eq!(11, sponge.stream_position()?);
+    sponge.commit()?;
+    assert_eq!("hello world", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn consume_destination() -> Result<(), io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("huge.txt");
+    fs::write(&test_path, vec![b'a'; 1024 * 1024])?;
+    let allocated = fs::metadata(&test_path)?.blocks();
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    let mut old = sponge.consume_destination()?;
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let found = old.read(&mut buf)?;
+        if 0 == found {
+            break;
+        }
+        buf[..found].make_ascii_uppercase();
+        sponge.write_all(&buf[..found])?;
+    }
+
+    // the old content has (mostly) gone, but the file is the same length
+    let consumed = fs::metadata(&test_path)?;
+    assert_eq!(1024 * 1024, consumed.len());
+    assert!(consumed.blocks() < allocated / 2, "{}", consumed.blocks());
+
+    sponge.commit()?;
+    let new = fs::read(&test_path)?;
+    assert_eq!(1024 * 1024, new.len());
+    assert!(new.iter().all(|&b| b'A' == b));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn consume_destination_release_fails() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("append-only.txt");
+    fs::write(&test_path, vec![b'a'; 1024 * 1024])?;
+
+    let sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    let mut old = sponge.consume_destination()?;
+    // the file's already open, but its storage can no longer be released
+    if !chattr("+a", &test_path) {
+        return Ok(());
+    }
+
+    let mut buf = vec![0u8; 64 * 1024];
+    let first = old.read(&mut buf);
+    let second = old.read(&mut buf);
+    let mut rest = Vec::new();
+    let rest = old.read_to_end(&mut rest).map(|_| rest);
+    assert!(chattr("-a", &test_path));
+
+    // the read which failed to release still returns its data; the next reports the failure
+    assert_eq!(64 * 1024, first?);
+    assert_eq!(io::ErrorKind::PermissionDenied, second.unwrap_err().kind());
+    assert_eq!(1024 * 1024 - 64 * 1024, rest?.len());
+    assert_eq!(vec![b'a'; 1024 * 1024], fs::read(&test_path)?);
+
+    Ok(())
+}
+
+#[test]
+fn clone_range_from_existing() -> Result<(), io::Error> {
+    use std::io::Seek;
+    use std::io::SeekFrom;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("large.bin");
+    let block = 4096;
+    let mut original = vec![b'a'; block];
+    original.extend(vec![b'b'; block]);
+    original.extend(vec![b'c'; block]);
+    fs::write(&test_path, &original)?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.clone_range_from_existing(0, block as u64)?;
+    sponge.clone_range_from_existing(2 * block as u64, block as u64)?;
+    sponge.seek(SeekFrom::Start(block as u64))?;
+    sponge.write_all(&vec![b'B'; block])?;
+    sponge.commit()?;
+
+    let found = fs::read(&test_path)?;
+    assert_eq!(3 * block, found.len());
+    assert!(found[..block].iter().all(|&b| b == b'a'));
+    assert!(found[block..2 * block].iter().all(|&b| b == b'B'));
+    assert!(found[2 * block..].iter().all(|&b| b == b'c'));
+
+    Ok(())
+}
+
+#[test]
+fn commit_with_stats() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("stats.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"first")?;
+    let stats = sponge.commit_with_stats()?;
+    assert_eq!(5, stats.bytes_written);
+    assert!(!stats.copy_fallback);
+    #[cfg(target_os = "linux")]
+    {
+        assert!(stats.linked_directly);
+        assert_eq!(0, stats.rename_attempts);
+    }
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"second")?;
+    let stats = sponge.commit_with_stats()?;
+    assert_eq!(6, stats.bytes_written);
+    assert!(!stats.linked_directly);
+    #[cfg(target_os = "linux")]
+    assert_eq!(1, stats.rename_attempts);
+
+    assert_eq!("second", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn atomic_write() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let dest = dir.path().join("dest");
+
+    tempfile_fast::atomic_write(&dest, b"hello")?;
+    assert_eq!("hello", read(fs::File::open(&dest)?));
+
+    tempfile_fast::atomic_write_durable(&dest, "world")?;
+    assert_eq!("world", read(fs::File::open(&dest)?));
+    assert_eq!(1, fs::read_dir(&dir)?.count());
+
+    Ok(())
+}
+
+#[test]
+fn preserve_times() -> Result<(), io::Error> {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    let dir = tempfile::TempDir::new()?;
+    let dest = dir.path().join("dest");
+    fs::write(&dest, b"old")?;
+    let then = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+    fs::File::options()
+        .write(true)
+        .open(&dest)?
+        .set_times(fs::FileTimes::new().set_accessed(then).set_modified(then))?;
+
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| -> Result<(), io::Error> {
+        let watcher = scope.spawn(|| {
+            while !done.load(Ordering::SeqCst) {
+                assert_eq!(then, fs::metadata(&dest).unwrap().modified().unwrap());
+            }
+        });
+
+        let mut sponge = tempfile_fast::Sponge::builder()
+            .preserve_times(true)
+            .new_for(&dest)?;
+        sponge.lock_metadata()?;
+        sponge.write_all(b"new")?;
+        sponge.commit()?;
+
+        done.store(true, Ordering::SeqCst);
+        watcher.join().unwrap();
+        Ok(())
+    })?;
+
+    assert_eq!("new", read(fs::File::open(&dest)?));
+    assert_eq!(then, fs::metadata(&dest)?.modified()?);
+
+    let fresh = dir.path().join("fresh");
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .preserve_times(true)
+        .new_for(&fresh)?;
+    sponge.write_all(b"new")?;
+    sponge.commit()?;
+    assert_ne!(then, fs::metadata(&fresh)?.modified()?);
+
+    // the retryable commit takes the same path
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .preserve_times(true)
+        .new_for(&dest)?;
+    sponge.write_all(b"retryable")?;
+    sponge.try_commit().map_err(|(e, _)| e)?;
+    assert_eq!("retryable", read(fs::File::open(&dest)?));
+    assert_eq!(then, fs::metadata(&dest)?.modified()?);
+
+    Ok(())
+}
+
+#[test]
+fn commit_to_many() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let versioned = dir.path().join("v2.txt");
+    let latest = dir.path().join("latest.txt");
+    let stable = dir.path().join("stable.txt");
+    fs::write(&latest, b"v1")?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&versioned)?;
+    sponge.write_all(b"v2")?;
+    sponge.commit_to_many(&[&latest, &stable])?;
+
+    for name in [&versioned, &latest, &stable] {
+        assert_eq!("v2", read(fs::File::open(name)?));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(3, fs::metadata(&versioned)?.nlink());
+    }
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&versioned)?;
+    sponge.write_all(b"v3")?;
+    let missing = dir.path().join("missing").join("latest.txt");
+    let e = sponge
+        .commit_to_many(&[&latest, &missing, &stable])
+        .unwrap_err();
+    assert!(e.to_string().contains("1 of 3"), "{e}");
+    assert_eq!("v3", read(fs::File::open(&latest)?));
+    assert_eq!("v2", read(fs::File::open(&stable)?));
+
+    let scratch = tempfile::TempDir::new()?;
+    let mut sponge = tempfile_fast::Sponge::new_for_with_scratch(&versioned, scratch.path())?;
+    sponge.write_all(b"v4")?;
+    sponge.commit_to_many(&[&latest])?;
+    assert_eq!("v4", read(fs::File::open(&latest)?));
+
+    // usually a tmpfs, unlike the temp dir
+    let shm = Path::new("/dev/shm");
+    if !shm.is_dir() {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if fs::metadata(shm)?.dev() == fs::metadata(dir.path())?.dev() {
+            return Ok(());
+        }
+    }
+    let scratch = tempfile::TempDir::new_in(shm)?;
+    let mut sponge = tempfile_fast::Sponge::new_for_with_scratch(&versioned, scratch.path())?;
+    sponge.write_all(b"v5")?;
+    let e = sponge.commit_to_many(&[&latest]).unwrap_err();
+    #[cfg(unix)]
+    assert_eq!(io::ErrorKind::CrossesDevices, e.kind(), "{e}");
+    assert_eq!("v4", read(fs::File::open(&versioned)?));
+    assert_eq!("v4", read(fs::File::open(&latest)?));
+
+    Ok(())
+}
+
+#[test]
+fn commit_stats_backend() -> Result<(), io::Error> {
+    use tempfile_fast::Backend;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("reported.txt");
+    fs::write(&test_path, b"old")?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    let backend = sponge.backend();
+    #[cfg(target_os = "linux")]
+    assert_eq!(Backend::Linux, backend);
+    sponge.write_all(b"new")?;
+    let stats = sponge.commit_with_stats()?;
+    assert_eq!(backend, stats.backend);
+    assert!(!stats.copy_fallback);
+    assert_eq!("new", read(fs::File::open(&test_path)?));
+
+    #[cfg(unix)]
+    {
+        let sponge = tempfile_fast::Sponge::builder()
+            .allow_non_regular(true)
+            .new_for("/dev/null")?;
+        assert_eq!(Backend::Direct, sponge.backend());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn best_effort_permissions() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("best-effort.txt");
+    fs::write(&test_path, b"old")?;
+
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .best_effort_permissions(true)
+        .new_for(&test_path)?;
+    sponge.write_all(b"new")?;
+    let stats = sponge.commit_with_stats()?;
+    assert!(!stats.permissions_skipped);
+    assert_eq!("new", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn best_effort_permissions_refused() -> Result<(), io::Error> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("nobodys.txt");
+    fs::write(&test_path, b"old")?;
+    fs::set_permissions(&test_path, fs::Permissions::from_mode(0o644))?;
+    // once the new file is given to nobody, only nobody can change its mode
+    std::os::unix::fs::chown(&test_path, Some(65534), Some(65534))?;
+
+    let commit = |best_effort: bool| -> Result<bool, io::Error> {
+        let mut sponge = tempfile_fast::Sponge::builder()
+            .best_effort_permissions(best_effort)
+            .new_for(&test_path)?;
+        sponge.write_all(b"new")?;
+        Ok(sponge.commit_with_stats()?.permissions_skipped)
+    };
+
+    let (strict, best_effort) = match without_fowner(|| (commit(false), commit(true))) {
+        Some(results) => results,
+        None => return Ok(()),
+    };
+
+    assert_eq!(io::ErrorKind::PermissionDenied, strict.unwrap_err().kind());
+    assert!(best_effort?);
+
+    let metadata = fs::metadata(&test_path)?;
+    assert_eq!(65534, metadata.uid());
+    assert_eq!(0o600, metadata.mode() & 0o7777);
+    assert_eq!("new", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn on_overlayfs() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let sponge = tempfile_fast::Sponge::new_for(dir.path().join("overlay.txt"))?;
+
+    // the test may, or may not, be running in a container
+    let overlay = sponge.on_overlayfs()?;
+    if !cfg!(target_os = "linux") {
+        assert!(!overlay);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn check_writable() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("checked.txt");
+
+    let sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.check_writable()?;
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn check_writable_read_only_dir() -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("checked.txt");
+    let sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o555))?;
+
+    // root can write anywhere, so check as someone who can't
+    let checked = as_nobody(&[], || sponge.check_writable());
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755))?;
+    let checked = match checked {
+        Some(checked) => checked,
+        None => return Ok(()),
+    };
+
+    let e = checked.unwrap_err();
+    assert_eq!(io::ErrorKind::PermissionDenied, e.kind());
+    assert!(e.to_string().contains("can't replace files in"), "{e}");
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn immutable_destination() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("immutable.txt");
+    fs::write(&test_path, b"old")?;
+    if !chattr("+i", &test_path) {
+        return Ok(());
+    }
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    let checked = sponge.check_writable().unwrap_err();
+    assert_eq!(io::ErrorKind::PermissionDenied, checked.kind());
+    sponge.write_all(b"new")?;
+    let e = sponge.commit().unwrap_err();
+    assert!(chattr("-i", &test_path));
+
+    assert_eq!(io::ErrorKind::PermissionDenied, e.kind());
+    assert!(e.to_string().contains("immutable"), "{e}");
+    assert_eq!("old", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn reset() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("reset.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"a failed attempt, which is quite long")?;
+    sponge.flush()?;
+    sponge.write_all(b" and partly buffered")?;
+
+    sponge.reset()?;
+    assert_eq!(0, sponge.bytes_written());
+    sponge.write_all(b"success")?;
+    sponge.commit()?;
+
+    assert_eq!("success", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn commit_audited() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("audited.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"audited")?;
+    let record = sponge.commit_audited()?;
+
+    assert_eq!(test_path, record.path);
+    assert_eq!(7, record.len);
+    assert_eq!(cfg!(target_os = "linux"), record.fast_path);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let found = fs::metadata(&test_path)?;
+        assert_eq!(found.mode(), record.mode);
+        assert_eq!((found.uid(), found.gid()), (record.uid, record.gid));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn commit_verified() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("verified.txt");
+    fs::write(&test_path, b"old")?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"truncated")?;
+    let e = sponge
+        .commit_verified(|file| Ok(read(file) == "truncated, but longer"))
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    assert_eq!("old", read(fs::File::open(&test_path)?));
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"complete")?;
+    sponge.commit_verified(|file| Ok(read(file) == "complete"))?;
+    assert_eq!("complete", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn commit_if_unchanged() -> Result<(), io::Error> {
+    fn changed(e: &io::Error) -> bool {
+        e.get_ref()
+            .is_some_and(|e| e.is::<tempfile_fast::DestinationChanged>())
+    }
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("shared.txt");
+    fs::write(&test_path, b"old")?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"mine")?;
+    sponge.commit_if_unchanged()?;
+    assert_eq!("mine", read(fs::File::open(&test_path)?));
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"mine again")?;
+    fs::write(&test_path, b"theirs")?;
+    assert!(changed(&sponge.commit_if_unchanged().unwrap_err()));
+    assert_eq!("theirs", read(fs::File::open(&test_path)?));
+
+    let created = dir.path().join("created.txt");
+    let mut sponge = tempfile_fast::Sponge::new_for(&created)?;
+    sponge.write_all(b"mine")?;
+    fs::write(&created, b"theirs")?;
+    assert!(changed(&sponge.commit_if_unchanged().unwrap_err()));
+    assert_eq!("theirs", read(fs::File::open(&created)?));
+
+    Ok(())
+}
+
+#[test]
+fn edit() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("edited.conf");
+
+    tempfile_fast::Sponge::edit(&test_path, |old| {
+        assert!(old.is_empty());
+        b"a = 1\n".to_vec()
+    })?;
+    tempfile_fast::Sponge::edit(&test_path, |old| [old, b"b = 2\n"].concat())?;
+
+    assert_eq!("a = 1\nb = 2\n", read(fs::File::open(&test_path)?));
+    assert_eq!(1, fs::read_dir(dir.path())?.count());
+
+    Ok(())
+}
+
+#[test]
+fn commit_job() -> Result<(), io::Error> {
+    use std::sync::mpsc;
+    use std::thread;
+
+    let dir = tempfile::TempDir::new()?;
+    let (send, recv) = mpsc::channel::<tempfile_fast::CommitJob>();
+    let worker = thread::spawn(move || -> Result<(), io::Error> {
+        for job in recv {
+            job.run()?;
+        }
+        Ok(())
+    });
+
+    for name in &["a.txt", "b.txt"] {
+        let mut sponge = tempfile_fast::Sponge::new_for(dir.path().join(name))?;
+        sponge.write_all(name.as_bytes())?;
+        let job = sponge.into_commit_job()?;
+        assert!(job.dest().ends_with(name));
+        send.send(job).unwrap();
+    }
+    drop(send);
+    worker.join().unwrap()?;
+
+    assert_eq!("a.txt", read(fs::File::open(dir.path().join("a.txt"))?));
+    assert_eq!("b.txt", read(fs::File::open(dir.path().join("b.txt"))?));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn commit_job_write_error() -> Result<(), io::Error> {
+    // writes to /dev/full always fail with ENOSPC
+    let mut sponge = tempfile_fast::Sponge::builder()
+        .allow_non_regular(true)
+        .new_for("/dev/full")?;
+    sponge.write_all(b"lost")?;
+
+    match sponge.into_commit_job() {
+        Ok(_) => panic!("wrote to /dev/full"),
+        Err(e) => {
+            assert_eq!(io::ErrorKind::StorageFull, e.kind());
+            assert!(e.to_string().contains("4 bytes unwritten"), "{}", e);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn with_lock() -> Result<(), io::Error> {
+    use std::thread;
+    use std::time::Duration;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("contended.conf");
+    let lock_path = dir.path().join("contended.conf.lock");
+
+    let mut first = tempfile_fast::Sponge::builder()
+        .with_lock(&lock_path)
+        .new_for(&test_path)?;
+    first.write_all(b"first")?;
+
+    let second = {
+        let test_path = test_path.clone();
+        let lock_path = lock_path.clone();
+        thread::spawn(move || -> Result<(), io::Error> {
+            let mut second = tempfile_fast::Sponge::builder()
+                .with_lock(&lock_path)
+                .new_for(&test_path)?;
+            // the lock is only granted once the first writer has finished
+            assert_eq!("first", read(fs::File::open(&test_path)?));
+            second.write_all(b"second")?;
+            second.commit()
+        })
+    };
+
+    thread::sleep(Duration::from_millis(50));
+    assert!(!test_path.exists());
+    first.commit()?;
+    second.join().unwrap()?;
+
+    assert_eq!("second", read(fs::File::open(&test_path)?));
+
+    Ok(())
+}
+
+#[test]
+fn commit_noclobber() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("default.conf");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"defaults")?;
+    assert!(sponge.commit_noclobber()?);
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"other defaults")?;
+    assert!(!sponge.commit_noclobber()?);
+
+    assert_eq!("defaults", read(fs::File::open(&test_path)?));
+    assert_eq!(1, fs::read_dir(dir.path())?.count());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn commit_in_place_non_atomic() -> Result<(), io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("watched.log");
+    let other_link = dir.path().join("other.log");
+    fs::write(&test_path, b"the old, longer, content")?;
+    fs::hard_link(&test_path, &other_link)?;
+    let inode = fs::metadata(&test_path)?.ino();
+    let mut held = fs::File::open(&test_path)?;
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"new")?;
+    sponge.commit_in_place_non_atomic()?;
+
+    // the old tail must go, however the copy is done; see copy.rs for each way
+    assert_eq!(inode, fs::metadata(&test_path)?.ino());
+    assert_eq!(3, fs::metadata(&test_path)?.len());
+    assert_eq!("new", read(&mut held));
+    assert_eq!("new", read(fs::File::open(&other_link)?));
+
+    Ok(())
+}
+
+#[test]
+fn debug() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let test_path = dir.path().join("debug.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"secret")?;
+    let debug = format!("{:?}", sponge);
+    assert!(debug.starts_with("Sponge { dest: "), "{}", debug);
+    assert!(debug.contains("debug.txt"), "{}", debug);
+    assert!(
+        debug.contains("backend: PersistableTempFile::"),
+        "{}",
+        debug
+    );
+    assert!(!debug.contains("secret"), "{}", debug);
+
+    Ok(())
+}
+
+#[test]
+fn parent_removed() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let parent = dir.path().join("short-lived");
+    let test_path = parent.join("orphan.txt");
+
+    let mut sponge = tempfile_fast::Sponge::new_for(&test_path)?;
+    sponge.write_all(b"orphaned")?;
+    fs::remove_dir_all(&parent)?;
+    let e = sponge.commit().unwrap_err();
+    assert_eq!(io::ErrorKind::NotFound, e.kind());
+    assert!(e.to_string().contains("no longer exists"), "{}", e);
+
+    // only the anonymous temporary file survives its directory being removed
+    #[cfg(target_os = "linux")]
+    {
+        let mut sponge = tempfile_fast::Sponge::builder()
+            .recreate_parent(true)
+            .new_for(&test_path)?;
+        sponge.write_all(b"adopted")?;
+        fs::remove_dir_all(&parent)?;
+        sponge.commit()?;
+        assert_eq!("adopted", read(fs::File::open(&test_path)?));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn publish_hardlink() -> Result<(), io::Error> {
+    let dir = tempfile::TempDir::new()?;
+    let store = dir.path().join("store");
+    let published = dir.path().join("published.txt");
+    fs::write(&store, b"stored")?;
+    fs::write(&published, b"old")?;
+
+    tempfile_fast::publish_hardlink(&store, &published)?;
+    assert_eq!("stored", read(fs::File::open(&published)?));
+
+    // publishing again is harmless
+    tempfile_fast::publish_hardlink(&store, &published)?;
+    assert_eq!(2, fs::read_dir(dir.path())?.count());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(fs::metadata(&store)?.ino(), fs::metadata(&published)?.ino());
+        assert_eq!(2, fs::metadata(&store)?.nlink());
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn root_path() {
+    match tempfile_fast::Sponge::new_for("/") {
+        Ok(_) => panic!("replacing the root"),
+        Err(e) => assert_eq!(io::ErrorKind::IsADirectory, e.kind()),
+    }
+}